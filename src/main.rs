@@ -1,64 +1,760 @@
 use chrono::{self, TimeZone};
 use dotenv::dotenv;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use notify::{
-    event::{CreateKind, DataChange, ModifyKind},
-    Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{CreateKind, DataChange, Flag, ModifyKind, RenameMode},
+    Config as NotifyConfig, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
 };
 use simple_logger::SimpleLogger;
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{hash_map::{DefaultHasher, RandomState}, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     env,
     fs::{self, File},
+    hash::{BuildHasher, Hash, Hasher},
     io::{BufRead, BufReader, Read, Write},
     net::TcpStream,
-    os::unix::fs::MetadataExt,
+    os::unix::{fs::MetadataExt, process::CommandExt},
     path::{Path, PathBuf},
     process::Command,
     sync::mpsc::channel,
-    sync::mpsc::TryRecvError::Empty,
+    sync::mpsc::RecvTimeoutError,
+    sync::mpsc::{sync_channel, Receiver, Sender, SyncSender},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    thread,
     time::Duration,
     time::Instant,
 };
 
-fn watch_for_file_changes(
-    src_dir: String,
+const SNIFF_MAX_LINE_BYTES: u64 = 64 * 1024;
+// ssh -o options whose name contains one of these are masked in logged commands, extended by LOG_REDACT_KEYS
+const DEFAULT_LOG_REDACT_KEYS: &[&str] = &["password", "passphrase", "secret", "token", "identityfile", "proxycommand"];
+
+// Serializes appends by transfer workers with rewrites by the delayed deletion thread
+static DELETE_QUEUE_LOCK: Mutex<()> = Mutex::new(());
+// Suffixed names claimed with a hard link by suffix_file_name, whose create event is not a new file
+static SUFFIX_LINKS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+// Serializes upload.log appends with their rotation, so a log is never rotated while it is written
+static UPLOAD_LOG_LOCK: Mutex<()> = Mutex::new(());
+// Marks events of files re-queued after changing during their transfer, whose name is already suffixed
+const REQUEUED_EVENT_INFO: &str = "rsync_csv_requeued";
+// Per table metrics bucket of failed files that were not matched to a table
+const UNKNOWN_TABLE: &str = "unknown";
+// upload.log reason of files whose header matches no template
+const NO_MATCHING_HEADERS_REASON: &str = "No matching table headers found.";
+// Prefix of transfer errors caused by a full destination, whose files are kept for a later transfer
+const DEST_FULL_REASON: &str = "Destination out of space";
+// Remote table directories that received their schema file, with SCHEMA_TRANSFER=once
+static SCHEMA_SENT_DIRS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+// Per table counters since startup, written to METRICS_FILE after each batch
+static TABLE_METRICS: Mutex<BTreeMap<String, TableMetrics>> = Mutex::new(BTreeMap::new());
+// Destinations ("host:dir") that passed the CHECK_DEST_WRITABLE write test
+static WRITABLE_DEST_DIRS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+// Serializes appends to ROLLUP_ENTRIES_FILE by transfer workers with its rewrite after a rollup
+static ROLLUP_ENTRIES_LOCK: Mutex<()> = Mutex::new(());
+// Set on SIGTERM or SIGINT, the watcher then hands over its pending batches and returns
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+// Whether the last batch was held because PAUSE_FILE existed, to log when processing resumes
+static PAUSED: AtomicBool = AtomicBool::new(false);
+// Size and consecutive growth count of files at their last stability check, for HOT_FILE_CHECKS
+static FILE_GROWTH: Mutex<BTreeMap<PathBuf, (u64, u32)>> = Mutex::new(BTreeMap::new());
+// Queue of prepared batches waiting for the transfer worker, with TRANSFER_PIPELINE_DEPTH
+static TRANSFER_PIPELINE: Mutex<Option<SyncSender<PreparedBatch>>> = Mutex::new(None);
+// Per file outcomes collected while the retry subcommand runs with --json, None otherwise
+static FILE_RESULTS: Mutex<Option<FileResults>> = Mutex::new(None);
+// Files deferred by MAX_TABLES_PER_CYCLE, handled first in the next cycle so every table gets its turn
+static TABLE_LIMIT_DEFERRED: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+// Keys set from the .env file, whose values dotenv already expanded
+static DOTENV_KEYS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+thread_local! {
+    // ID of the batch handled by the current thread with BATCH_ID_FORMAT, prefixed to its log lines
+    static BATCH_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+// Number of ssh based processes running, bounded by MAX_SSH_CONNECTIONS
+static SSH_CONNECTIONS: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+
+#[derive(Debug)]
+struct Config {
+    src_dirs: Vec<String>,
     dest_user: String,
     dest_host: String,
     dest_dir: String,
-    hashmap: HashMap<String, String>,
+    template_dirs: Vec<String>,
+    template_manifest: Option<String>,
     file_suffix: String,
     csv_event_wait_seconds: u64,
     csv_event_upper_limit: u64,
+    min_file_age_seconds: u64,
+    metadata_time_format: String,
+    metadata_timezone: TimestampZone,
+    sniff_extensionless: bool,
+    max_pending_events: usize,
+    header_row_index: usize,
+    dest_subdir_map: Vec<(String, String)>,
+    archive_dir: Option<String>,
+    archive_metadata: bool,
+    archive_mode: ArchiveMode,
+    strict_column_count: bool,
+    ssh_proxy_command: Option<String>,
+    generate_md5_sidecar: bool,
+    upload_log_format: UploadLogFormat,
+    header_match_mode: HeaderMatchMode,
+    prefix_match_tables: Vec<String>,
+    rsync_parallelism: usize,
+    inter_transfer_delay_seconds: u64,
+    global_bwlimit: u64,
+    ssh_control_path: Option<String>,
+    ssh_control_persist: String,
+    batch_manifest: bool,
+    batch_manifest_name: String,
+    batch_manifest_format: ManifestFormat,
+    rollup_manifest: bool,
+    rollup_manifest_name: String,
+    rollup_manifest_dir: Option<String>,
+    rollup_manifest_times: Vec<chrono::NaiveTime>,
+    rollup_entries_file: String,
+    source_tag: SourceTag,
+    ready_subdir: Option<String>,
+    header_match_retries: u32,
+    header_match_retry_interval_ms: u64,
+    rsync_chmod: Option<String>,
+    max_file_bytes: u64,
+    delete_delay_seconds: u64,
+    delete_queue_file: String,
+    fanout_hosts: Vec<String>,
+    fanout_policy: FanoutPolicy,
+    transfer_jitter_max_ms: u64,
+    trigger_on: TriggerOn,
+    column_rename_map: Vec<(String, String, String)>,
+    rsync_password_file: Option<String>,
+    rsync_password_env: Option<String>,
+    log_redact_keys: Vec<String>,
+    source_hostname: Option<String>,
+    atomic_publish: bool,
+    atomic_publish_staging_dir: String,
+    dest_file_name: Option<String>,
+    unmatched_is_error: bool,
+    per_table_debounce: bool,
+    max_ssh_connections: usize,
+    header_drift_threshold: f64,
+    respect_writer_locks: bool,
+    skip_empty_tables: bool,
+    empty_table_action: EmptyTableAction,
+    empty_table_quarantine_dir: Option<String>,
+    allowed_hours: Option<AllowedHours>,
+    pause_file: Option<String>,
+    rsync_files_from: bool,
+    success_webhook_url: Option<String>,
+    success_webhook_min_files: usize,
+    success_webhook_min_bytes: u64,
+    strict_header: bool,
+    ignore_header_quotes: bool,
+    rsync_progress_interval_seconds: u64,
+    remote_dir_mode: Option<String>,
+    metadata_date_columns: Vec<(String, String)>,
+    rsync_retries: u32,
+    dead_letter_dir: Option<String>,
+    requeue_changed_files: bool,
+    follow_remounts: bool,
+    ordered_tables: Vec<String>,
+    check_dest_free_space: bool,
+    check_dest_writable: bool,
+    dest_free_space_margin_percent: u64,
+    table_schema_files: Vec<(String, String)>,
+    schema_transfer: SchemaTransfer,
+    metrics_file: Option<String>,
+    validate_utf8: bool,
+    validate_utf8_bytes: u64,
+    invalid_file_quarantine_dir: Option<String>,
+    symlink_metadata: SymlinkMetadata,
+    include_globs: Vec<String>,
+    normalize_line_endings: bool,
+    batch_id_format: Option<BatchIdFormat>,
+    source_max_age_hours: u64,
+    source_purge_interval_minutes: u64,
+    source_purge_quarantine_dir: Option<String>,
+    transfer_tool: TransferTool,
+    status_file: Option<String>,
+    rsync_delay_updates: bool,
+    hot_file_checks: u32,
+    rsync_bin: String,
+    rsync_times: RsyncTimes,
+    max_tables_per_cycle: usize,
+    dest_full_webhook_url: Option<String>,
+    table_dest_owners: Vec<(String, String)>,
+    dest_chown_command: String,
+    keep_local_metadata: bool,
+    metadata_ledger_dir: Option<String>,
+    tolerate_extra_trailing_columns: bool,
+    upload_log_rotate_hours: u64,
+    upload_log_compress: bool,
+    upload_log_archive_dir: Option<String>,
+    event_merge_window_ms: u64,
+    validate_types: bool,
+    table_column_types: Vec<(String, Vec<(String, ColumnType)>)>,
+    types_allow_empty: bool,
+    rsync_rsh: Option<String>,
+    transfer_pipeline_depth: usize,
+    dir_quiet_seconds: u64,
+    hot_file_webhook_url: Option<String>,
+    run_as_uid: Option<u32>,
+    run_as_gid: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SourceTag {
+    None,
+    Xattr,
+    Marker,
+}
+
+impl std::str::FromStr for SourceTag {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(SourceTag::None),
+            "xattr" => Ok(SourceTag::Xattr),
+            "marker" => Ok(SourceTag::Marker),
+            other => Err(format!("Expected \"none\", \"xattr\" or \"marker\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ManifestFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for ManifestFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "csv" => Ok(ManifestFormat::Csv),
+            "json" => Ok(ManifestFormat::Json),
+            other => Err(format!("Expected \"csv\" or \"json\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HeaderMatchMode {
+    Exact,
+    Prefix,
+}
+
+impl std::str::FromStr for HeaderMatchMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "exact" => Ok(HeaderMatchMode::Exact),
+            "prefix" => Ok(HeaderMatchMode::Prefix),
+            other => Err(format!("Expected \"exact\" or \"prefix\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UploadLogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for UploadLogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "text" => Ok(UploadLogFormat::Text),
+            "json" => Ok(UploadLogFormat::Json),
+            other => Err(format!("Expected \"text\" or \"json\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TriggerOn {
+    Create,
+    Modify,
+    Both,
+}
+
+impl std::str::FromStr for TriggerOn {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "create" => Ok(TriggerOn::Create),
+            "modify" => Ok(TriggerOn::Modify),
+            "both" => Ok(TriggerOn::Both),
+            other => Err(format!("Expected \"create\", \"modify\" or \"both\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FanoutPolicy {
+    All,
+    Any,
+}
+
+impl std::str::FromStr for FanoutPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "all" => Ok(FanoutPolicy::All),
+            "any" => Ok(FanoutPolicy::Any),
+            other => Err(format!("Expected \"all\" or \"any\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveMode {
+    Move,
+    Copy,
+}
+
+impl std::str::FromStr for ArchiveMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "move" => Ok(ArchiveMode::Move),
+            "copy" => Ok(ArchiveMode::Copy),
+            other => Err(format!("Expected \"move\" or \"copy\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SchemaTransfer {
+    Batch,
+    Once,
+}
+
+impl std::str::FromStr for SchemaTransfer {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "batch" => Ok(SchemaTransfer::Batch),
+            "once" => Ok(SchemaTransfer::Once),
+            other => Err(format!("Expected \"batch\" or \"once\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SymlinkMetadata {
+    Target,
+    Link,
+}
+
+impl std::str::FromStr for SymlinkMetadata {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "target" => Ok(SymlinkMetadata::Target),
+            "link" => Ok(SymlinkMetadata::Link),
+            other => Err(format!("Expected \"target\" or \"link\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BatchIdFormat {
+    Uuid,
+    Timestamp,
+}
+
+impl std::str::FromStr for BatchIdFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "uuid" => Ok(BatchIdFormat::Uuid),
+            "timestamp" => Ok(BatchIdFormat::Timestamp),
+            other => Err(format!("Expected \"uuid\" or \"timestamp\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EmptyTableAction {
+    Keep,
+    Delete,
+    Quarantine,
+}
+
+impl std::str::FromStr for EmptyTableAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "keep" => Ok(EmptyTableAction::Keep),
+            "delete" => Ok(EmptyTableAction::Delete),
+            "quarantine" => Ok(EmptyTableAction::Quarantine),
+            other => Err(format!("Expected \"keep\", \"delete\" or \"quarantine\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    String,
+    Integer,
+    Decimal,
+    Boolean,
+    Date,
+    DateTime,
+}
+
+impl std::str::FromStr for ColumnType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "string" => Ok(ColumnType::String),
+            "integer" => Ok(ColumnType::Integer),
+            "decimal" => Ok(ColumnType::Decimal),
+            "boolean" => Ok(ColumnType::Boolean),
+            "date" => Ok(ColumnType::Date),
+            "datetime" => Ok(ColumnType::DateTime),
+            other => Err(format!(
+                "Expected \"string\", \"integer\", \"decimal\", \"boolean\", \"date\" or \"datetime\", got {:?}",
+                other
+            )),
+        }
+    }
+}
+
+impl ColumnType {
+    fn accepts(self, value: &str) -> bool {
+        // Whether a non-empty field value is of this type
+        match self {
+            ColumnType::String => true,
+            ColumnType::Integer => value.parse::<i64>().is_ok(),
+            // Rust also parses "inf" and "NaN" as floats, which are not decimals
+            ColumnType::Decimal => {
+                value.parse::<f64>().is_ok() && value.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+            }
+            ColumnType::Boolean => ["true", "false", "1", "0"].contains(&value.to_lowercase().as_str()),
+            ColumnType::Date => chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            ColumnType::DateTime => parse_date_time(value).is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RsyncTimes {
+    Preserve,
+    Arrival,
+}
+
+impl std::str::FromStr for RsyncTimes {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "preserve" => Ok(RsyncTimes::Preserve),
+            "arrival" => Ok(RsyncTimes::Arrival),
+            other => Err(format!("Expected \"preserve\" or \"arrival\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransferTool {
+    Rsync,
+    Scp,
+}
+
+impl std::str::FromStr for TransferTool {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "rsync" => Ok(TransferTool::Rsync),
+            "scp" => Ok(TransferTool::Scp),
+            other => Err(format!("Expected \"rsync\" or \"scp\", got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AllowedHours {
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+}
+
+impl AllowedHours {
+    fn contains(&self, time: chrono::NaiveTime) -> bool {
+        // A window ending before it starts (e.g. 22:00-06:00) spans midnight
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl std::str::FromStr for AllowedHours {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse_time = |time: &str| {
+            chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|e| format!("Invalid time {:?}: {}", time.trim(), e))
+        };
+        match value.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse_time(start)?, parse_time(end)?);
+                if start == end {
+                    return Err(format!("Empty window {:?}, start and end are equal", value));
+                }
+                Ok(AllowedHours { start, end })
+            }
+            None => Err(format!("Expected HH:MM-HH:MM, got {:?}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimestampZone {
+    Local,
+    Utc,
+}
+
+impl std::str::FromStr for TimestampZone {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "local" => Ok(TimestampZone::Local),
+            "utc" => Ok(TimestampZone::Utc),
+            other => Err(format!("Expected \"local\" or \"utc\", got {:?}", other)),
+        }
+    }
+}
+
+struct Template {
+    // Normalized template header, kept for exact comparison, metadata and logging
+    headers: String,
+    table_name: String,
+}
+
+// Templates bucketed by header_hash. Templates with the same column set in a different order share a bucket.
+type TemplateMap = HashMap<u64, Vec<Template>>;
+
+fn watch_for_file_changes(config: &Config, hashmap: TemplateMap) -> notify::Result<()> {
+    // With TRANSFER_PIPELINE_DEPTH, batches are transferred by a worker while the watcher prepares the next ones
+    if config.transfer_pipeline_depth == 0 {
+        return watch_files(config, hashmap, None);
+    }
+    let (batch_tx, batch_rx) = sync_channel(config.transfer_pipeline_depth);
+    let (requeue_tx, requeue_rx) = channel();
+    thread::scope(|scope| {
+        scope.spawn(move || run_transfer_pipeline(batch_rx, requeue_tx, config));
+        *TRANSFER_PIPELINE.lock().unwrap() = Some(batch_tx);
+        let result = watch_files(config, hashmap, Some(requeue_rx));
+        // Closing the queue lets the worker transfer the queued batches and stop, the scope waits for it
+        TRANSFER_PIPELINE.lock().unwrap().take();
+        info!("Watcher stopped, transferring queued batches");
+        result
+    })
+}
+
+fn run_transfer_pipeline(batch_rx: Receiver<PreparedBatch>, requeue_tx: Sender<notify::Event>, config: &Config) {
+    // Transfer prepared batches in the order they were queued. Events of re-queued files are sent back to the
+    // watcher.
+    for mut batch in batch_rx {
+        let _batch_id_scope = BatchIdScope::enter(batch.batch_id.take());
+        for requeued_event in transfer_batch(&batch.rsync_hashmap, &mut batch.summary, batch.timing, config) {
+            let _ = requeue_tx.send(requeued_event);
+        }
+    }
+}
+
+struct PreparedBatch {
+    // A batch whose files were renamed and given metadata, waiting to be transferred
+    rsync_hashmap: HashMap<String, HashMap<String, Vec<String>>>,
+    summary: BatchSummary,
+    timing: (chrono::DateTime<chrono::Local>, Instant),
+    batch_id: Option<String>,
+}
+
+fn watch_files(
+    config: &Config,
+    hashmap: TemplateMap,
+    requeue_rx: Option<Receiver<notify::Event>>,
 ) -> notify::Result<()> {
     let (tx, rx) = channel();
+    // The signal thread wakes the watcher through its event channel, so it doesn't wait for the next timeout
+    let shutdown_tx = tx.clone();
+    let mut signals = Signals::new([SIGTERM, SIGINT]).map_err(notify::Error::io)?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}, stopping the watcher", signal);
+            SHUTDOWN.store(true, Ordering::SeqCst);
+            let _ = shutdown_tx.send(Err(notify::Error::generic("Shutdown requested")));
+        }
+    });
 
     // Initialize watcher, set poll interval and watch path
     let mut watcher = RecommendedWatcher::new(
         tx,
-        Config::default().with_poll_interval(Duration::from_secs(2)),
+        NotifyConfig::default().with_poll_interval(Duration::from_secs(2)),
     )
     .unwrap();
 
     // If watcher errors out, log error and return
-    if let Err(err) = watcher.watch(src_dir.as_ref(), RecursiveMode::Recursive) {
-        error!("Failed to watch directory: {:?}", err);
-        Err(err)?;
+    // Each watched directory is kept with its device and inode to detect remounts with FOLLOW_REMOUNTS
+    let mut watch_dirs: Vec<(PathBuf, Option<(u64, u64)>)> = Vec::new();
+    for src_dir in &config.src_dirs {
+        let watch_dir = watch_dir_path(src_dir, config);
+        if let Err(err) = watcher.watch(&watch_dir, RecursiveMode::Recursive) {
+            error!("Failed to watch directory {:?}: {:?}", watch_dir, err);
+            Err(err)?;
+        }
+        let identity = dir_identity(&watch_dir);
+        watch_dirs.push((watch_dir, identity));
     }
 
     let mut event_vec: Vec<notify::Event> = Vec::new();
     let mut last_event_time = Instant::now();
+    // With PER_TABLE_DEBOUNCE, events of matched files wait per table with their own last event time.
+    // event_vec then only holds events whose table could not be resolved yet.
+    let mut table_events: HashMap<String, (Vec<notify::Event>, Instant)> = HashMap::new();
+    // With DIR_QUIET_SECONDS, the first and last event time of each directory with events in event_vec
+    let mut dir_event_times: HashMap<PathBuf, (Instant, Instant)> = HashMap::new();
+    // With EVENT_MERGE_WINDOW_MS, the last event time of files with a pending event
+    let mut file_event_times: HashMap<PathBuf, Instant> = HashMap::new();
+    // Rename trackers of files moved away inside the watched tree, used to tell them apart from move-ins
+    let mut internal_renames: HashSet<usize> = HashSet::new();
+    let mut last_rollup_check = zone_now(config.metadata_timezone);
+    let mut last_source_purge = Instant::now();
+    let mut last_upload_log_rotation = Instant::now();
+    let mut watch_status = WatchStatus::default();
+    // Whether events past MAX_PENDING_EVENTS are being dropped while PAUSE_FILE exists, to warn once per pause
+    let mut dropping_paused_events = false;
 
     loop {
-        match rx.try_recv() {
+        // Block until an event arrives or the earliest pending batch is due for flushing
+        let next_flush = next_flush_time(&event_vec, last_event_time, &table_events, &dir_event_times, config);
+        let recv_timeout = match next_flush {
+            None => Duration::from_secs(config.csv_event_wait_seconds.max(1)),
+            Some(flush_time) => flush_time.saturating_duration_since(Instant::now()),
+        };
+        let received = rx.recv_timeout(recv_timeout);
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            // Pending files are handled now, as nothing would pick them up after a restart
+            flush_pending_batches(table_events, event_vec, &hashmap, config);
+            return Ok(());
+        }
+        match received {
             Ok(res) => match res {
-                Ok(event) => match event.kind {
-                    EventKind::Create(CreateKind::File)
-                    | EventKind::Modify(ModifyKind::Data(DataChange::Any)) => {
+                Ok(event) => {
+                    // Some backends signal a queue overflow with a rescan event, any events in it are lost
+                    if event.flag() == Some(Flag::Rescan) {
+                        error!(
+                            "Watcher event queue overflowed, file events may have been missed. Rescanning the \
+                             watched directories. Frequent overflows call for a larger \
+                             fs.inotify.max_queued_events sysctl."
+                        );
+                        let pending_files: HashSet<&PathBuf> = event_vec
+                            .iter()
+                            .chain(table_events.values().flat_map(|(events, _)| events))
+                            .flat_map(|event| &event.paths)
+                            .collect();
+                        let rescan_events = rescan_source_files(&watch_dirs, &pending_files, &hashmap, config);
+                        info!("Rescan found {} file(s) without pending events", rescan_events.len());
+                        for rescan_event in &rescan_events {
+                            queue_event(
+                                rescan_event,
+                                &mut event_vec,
+                                &mut last_event_time,
+                                &mut table_events,
+                                &mut dir_event_times,
+                                &mut file_event_times,
+                                &hashmap,
+                                config,
+                            );
+                        }
+                    }
+                    // Events without paths (e.g. overflow or rescan events) have no file to handle
+                    if event.paths.is_empty() {
+                        debug!("Ignoring event without paths: {:?}", event);
+                    }
+                    let is_suffix_link = event.kind == EventKind::Create(CreateKind::File)
+                        && event.paths.first().is_some_and(|path| SUFFIX_LINKS.lock().unwrap().remove(path));
+                    let is_file_ready_event = !event.paths.is_empty() && match event.kind {
+                        EventKind::Create(CreateKind::File) => {
+                            config.ready_subdir.is_none()
+                                && config.trigger_on != TriggerOn::Modify
+                                && !is_suffix_link
+                        }
+                        EventKind::Modify(ModifyKind::Data(DataChange::Any)) => {
+                            config.ready_subdir.is_none() && config.trigger_on != TriggerOn::Create
+                        }
+                        // In the incoming/ready layout only files moved into the ready directory are
+                        // picked up. Renames within it (e.g. adding the timestamp suffix) are not move-ins.
+                        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                            if let (Some(_), Some(tracker)) = (&config.ready_subdir, event.attrs.tracker()) {
+                                internal_renames.insert(tracker);
+                            }
+                            false
+                        }
+                        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                            config.ready_subdir.is_some()
+                                && !event.attrs.tracker().is_some_and(|t| internal_renames.remove(&t))
+                        }
+                        _ => false,
+                    };
+                    if is_file_ready_event && !is_included(&event.paths[0], config) {
+                        debug!("Ignoring {:?}, it matches no INCLUDE_GLOBS pattern", event.paths[0]);
+                    } else if is_file_ready_event {
                         if event.paths[0].extension().and_then(|s| s.to_str()) == Some("csv") {
                             info!("CSV file event detected: {:?}", event);
-                            event_vec.push(event.clone());
-                            last_event_time = Instant::now();
+                            queue_event(
+                                &event,
+                                &mut event_vec,
+                                &mut last_event_time,
+                                &mut table_events,
+                                &mut dir_event_times,
+                                &mut file_event_times,
+                                &hashmap,
+                                config,
+                            );
+                        } else if config.sniff_extensionless
+                            && event.paths[0].extension().is_none()
+                            && event.paths[0].is_file()
+                            && sniff_csv_header(&event.paths[0], &hashmap, config)
+                        {
+                            info!("Extensionless CSV file event detected: {:?}", event);
+                            queue_event(
+                                &event,
+                                &mut event_vec,
+                                &mut last_event_time,
+                                &mut table_events,
+                                &mut dir_event_times,
+                                &mut file_event_times,
+                                &hashmap,
+                                config,
+                            );
                         }
                         if let Ok(metadata) = fs::symlink_metadata(&event.paths[0]) {
                             if metadata.file_type().is_symlink() {
@@ -68,352 +764,4772 @@ fn watch_for_file_changes(
                                 }
                             }
                         }
-                    },
-                    _ => (),
+                    }
                 },
                 Err(e) => error!("Watch error: {:?}", e),
             },
-            Err(e) => {
-                if e == Empty {
-                    ();
-                } else {
-                    error!("Error receiving event: {:?}", e);
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("Watcher event channel disconnected. Stopping watcher.");
+                flush_pending_batches(table_events, event_vec, &hashmap, config);
+                return Err(notify::Error::generic("Watcher event channel disconnected"));
+            }
+        }
+        // Files the transfer worker re-queued wait for the next cycle like deferred files
+        for requeued_event in requeue_rx.iter().flat_map(Receiver::try_iter) {
+            queue_event(
+                &requeued_event,
+                &mut event_vec,
+                &mut last_event_time,
+                &mut table_events,
+                &mut dir_event_times,
+                &mut file_event_times,
+                &hashmap,
+                config,
+            );
+        }
+        if config.follow_remounts {
+            rewatch_remounted_dirs(&mut watcher, &mut watch_dirs);
+        }
+        // A rollup due while paused is transferred once processing resumes
+        if config.rollup_manifest && !is_paused(config) {
+            let now = zone_now(config.metadata_timezone);
+            if rollup_due(&config.rollup_manifest_times, last_rollup_check, now) {
+                transfer_rollup_manifest(config);
+            }
+            last_rollup_check = now;
+        }
+        if config.source_max_age_hours > 0
+            && last_source_purge.elapsed() >= Duration::from_secs(config.source_purge_interval_minutes * 60)
+        {
+            let pending_files: HashSet<&PathBuf> = event_vec
+                .iter()
+                .chain(table_events.values().flat_map(|(events, _)| events))
+                .flat_map(|event| &event.paths)
+                .collect();
+            purge_stale_source_files(&watch_dirs, &pending_files, &hashmap, config);
+            last_source_purge = Instant::now();
+        }
+        if config.upload_log_rotate_hours > 0
+            && last_upload_log_rotation.elapsed() >= Duration::from_secs(config.upload_log_rotate_hours * 3600)
+        {
+            rotate_upload_logs(&watch_dirs, config);
+            last_upload_log_rotation = Instant::now();
+        }
+        let pending_event_count =
+            event_vec.len() + table_events.values().map(|(events, _)| events.len()).sum::<usize>();
+        let backpressure =
+            config.max_pending_events > 0 && pending_event_count >= config.max_pending_events;
+        let paused = is_paused(config);
+        if !paused {
+            dropping_paused_events = false;
+        }
+        // A flush would only hold the files again while paused, so events past the cap are dropped instead
+        let backpressure = if backpressure && paused {
+            let dropped = drop_excess_events(&mut event_vec, &mut table_events, config.max_pending_events);
+            if !dropping_paused_events {
+                warn!(
+                    "Pending event count reached MAX_PENDING_EVENTS ({}) while paused. Dropping further events \
+                     until processing resumes, their files are picked up again on their next event.",
+                    config.max_pending_events
+                );
+                dropping_paused_events = true;
+            }
+            if dropped > 0 {
+                debug!("Dropped {} event(s) past MAX_PENDING_EVENTS while paused", dropped);
+            }
+            false
+        } else {
+            backpressure
+        };
+        if backpressure {
+            warn!(
+                "Pending event count {} reached MAX_PENDING_EVENTS ({}). Forcing flush.",
+                pending_event_count,
+                config.max_pending_events
+            );
+        }
+        // Each table flushes on its own timer, so a busy table doesn't hold back the others
+        let due_tables: Vec<String> = table_events
+            .iter()
+            .filter(|(_, (events, table_event_time))| {
+                table_event_time.elapsed().as_secs() > config.csv_event_wait_seconds
+                    || events.len() > config.csv_event_upper_limit as usize
+                    || backpressure
+            })
+            .map(|(table_name, _)| table_name.clone())
+            .collect();
+        for table_name in due_tables {
+            let (events, _) = table_events.remove(&table_name).unwrap();
+            info!("Flushing {} event(s) of table {}", events.len(), table_name);
+            match handle_csv_file_event(config, &hashmap, &events) {
+                Ok((deferred_events, summary)) => {
+                    watch_status.record_batch(&summary);
+                    if !deferred_events.is_empty() {
+                        table_events.insert(table_name, (deferred_events, Instant::now()));
+                    }
                 }
+                Err(e) => error!("Error handling csv file event: {:?}", e),
             }
         }
-        if (last_event_time.elapsed().as_secs() > csv_event_wait_seconds
-            || event_vec.len() > csv_event_upper_limit as usize)
+        if config.dir_quiet_seconds > 0 {
+            // Directories whose events moved to a table batch are dropped. Directories of deferred and re-queued
+            // events start a new quiet period.
+            dir_event_times.retain(|dir, _| event_vec.iter().any(|event| event_dir(event) == *dir));
+            let now = Instant::now();
+            for event in &event_vec {
+                dir_event_times.entry(event_dir(event)).or_insert((now, now));
+            }
+            let flush_all = event_vec.len() > config.csv_event_upper_limit as usize || backpressure;
+            let due_dirs: HashSet<PathBuf> = dir_event_times
+                .iter()
+                .filter(|(_, (first_event_time, last_event_time))| {
+                    flush_all
+                        || last_event_time.elapsed() >= Duration::from_secs(config.dir_quiet_seconds)
+                        || first_event_time.elapsed() > Duration::from_secs(config.csv_event_wait_seconds)
+                })
+                .map(|(dir, _)| dir.clone())
+                .collect();
+            if !due_dirs.is_empty() {
+                let (due_events, waiting_events): (Vec<notify::Event>, Vec<notify::Event>) =
+                    event_vec.drain(..).partition(|event| due_dirs.contains(&event_dir(event)));
+                event_vec = waiting_events;
+                dir_event_times.retain(|dir, _| !due_dirs.contains(dir));
+                info!("Flushing {} event(s) of {} directory(ies)", due_events.len(), due_dirs.len());
+                match handle_csv_file_event(config, &hashmap, &due_events) {
+                    Ok((deferred_events, summary)) => {
+                        watch_status.record_batch(&summary);
+                        event_vec.extend(deferred_events);
+                    }
+                    Err(e) => error!("Error handling csv file event: {:?}", e),
+                }
+            }
+        } else if (last_event_time.elapsed().as_secs() > config.csv_event_wait_seconds
+            || event_vec.len() > config.csv_event_upper_limit as usize
+            || backpressure)
             && !event_vec.is_empty()
         {
-            match handle_csv_file_event(
-                &dest_user,
-                &dest_host,
-                &dest_dir,
-                &hashmap,
-                &file_suffix,
-                &event_vec,
-            ) {
-                Ok(_) => event_vec.clear(),
+            match handle_csv_file_event(config, &hashmap, &event_vec) {
+                Ok((deferred_events, summary)) => {
+                    watch_status.record_batch(&summary);
+                    // Files deferred by the minimum age check are carried over to the next cycle
+                    if !deferred_events.is_empty() {
+                        last_event_time = Instant::now();
+                    }
+                    event_vec = deferred_events;
+                }
                 Err(e) => error!("Error handling csv file event: {:?}", e),
             }
         }
+        if let Some(status_file) = &config.status_file {
+            // Written at most once per second, events can arrive much more often
+            if watch_status.last_write.is_none_or(|last_write| last_write.elapsed() >= Duration::from_secs(1)) {
+                let pending_event_count =
+                    event_vec.len() + table_events.values().map(|(events, _)| events.len()).sum::<usize>();
+                let next_flush =
+                    next_flush_time(&event_vec, last_event_time, &table_events, &dir_event_times, config);
+                if let Err(e) = watch_status.write(status_file, pending_event_count, next_flush, config) {
+                    error!("Failed to write status file {}: {}", status_file, e);
+                }
+            }
+        }
     }
 }
 
-fn handle_csv_file_event(
-    dest_user: &str,
-    dest_host: &str,
-    dest_dir: &str,
-    hashmap: &HashMap<String, String>,
-    file_suffix: &str,
-    event_vec: &Vec<notify::Event>,
-) -> std::io::Result<()> {
-    // Handle csv file events
-    info!(
-        "Handling CSV file events. Total event count: {:?}",
-        event_vec.len()
-    );
-    // debug!("Event Vec: {:?}", event_vec);
-    /*
-    Rsync hashmap structure:
-    {
-        "table_name": {
-            "src_files": [src_file...],
-            "metadata_files": [metadata_file...]
-            "uploaded_by": [username...]
+fn next_flush_time(
+    event_vec: &[notify::Event],
+    last_event_time: Instant,
+    table_events: &HashMap<String, (Vec<notify::Event>, Instant)>,
+    dir_event_times: &HashMap<PathBuf, (Instant, Instant)>,
+    config: &Config,
+) -> Option<Instant> {
+    // Time the first pending batch is due for its timed flush, None if no events are pending
+    let wait = Duration::from_secs(config.csv_event_wait_seconds + 1);
+    let table_flush_times = table_events.values().map(|(_, table_event_time)| *table_event_time + wait);
+    // With DIR_QUIET_SECONDS, a directory is due once quiet, or CSV_EVENT_WAIT_SECONDS after its first event
+    let dir_flush_times = dir_event_times.values().map(|(first_event_time, last_event_time)| {
+        (*last_event_time + Duration::from_secs(config.dir_quiet_seconds)).min(*first_event_time + wait)
+    });
+    match config.dir_quiet_seconds {
+        0 => table_flush_times.chain((!event_vec.is_empty()).then_some(last_event_time + wait)).min(),
+        _ => table_flush_times.chain(dir_flush_times).min(),
+    }
+}
+
+fn event_dir(event: &notify::Event) -> PathBuf {
+    // Directory of the file of an event
+    event.paths[0].parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+#[derive(Default)]
+struct WatchStatus {
+    // Watch loop state since startup reported in STATUS_FILE
+    last_batch_time: Option<String>,
+    tables_seen: BTreeSet<String>,
+    last_write: Option<Instant>,
+}
+
+impl WatchStatus {
+    fn record_batch(&mut self, summary: &BatchSummary) {
+        // Batches held entirely (e.g. while paused) handle no file and are not recorded
+        if summary.total_files == 0 {
+            return;
         }
+        self.last_batch_time = Some(chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+        self.tables_seen.extend(summary.table_counts.keys().cloned());
     }
-     */
-    let mut rsync_hashmap: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
-    for event in event_vec.iter() {
-        let src_file_path = event.paths[0].to_str().unwrap();
-        let src_file_basename = event.paths[0].file_name().unwrap().to_str().unwrap();
-        let match_result = match_col_headers(src_file_path, &hashmap);
-        match match_result {
-            Ok(table_name) => {
-                if !table_name.is_empty() {
-                    let username = get_file_owner(src_file_path).unwrap();
-                    let src_file_with_suffix =
-                        suffix_file_name(src_file_path, &file_suffix)?;
-                    info!("Source file with suffix: {:?}", src_file_with_suffix);
-                    let metadata_file = match create_metadata_file(&src_file_with_suffix) {
-                        Ok(file) => file,
-                        Err(e) => {
-                            error!("Error creating metadata file: {:?}", e);
-                            String::new()
-                        }
-                    };
-                    let table_entry = rsync_hashmap.entry(table_name).or_insert(HashMap::new());
-                    table_entry
-                        .entry("src_files".to_string())
-                        .or_insert(Vec::new())
-                        .push(src_file_with_suffix);
-                    table_entry
-                        .entry("metadata_files".to_string())
-                        .or_insert(Vec::new())
-                        .push(metadata_file);
-                    table_entry
-                        .entry("uploaded_by".to_string())
-                        .or_insert(Vec::new())
-                        .push(username);
-                }
-            },
-            Err(e) => {
-                error!("Error matching column headers: {:?}", e);
-                match &event.paths[0].parent() {
-                    Some(log_dir) => log_upload_status(
-                        log_dir.to_str().unwrap(),
-                        format!("Upload failed! File: {src_file_basename} Reason: {e}").to_string(),
-                    ),
-                    None => error!("Failed to get parent directory of source file."),
-                }
-            }
+
+    fn write(
+        &mut self,
+        status_file: &str,
+        pending_event_count: usize,
+        next_flush: Option<Instant>,
+        config: &Config,
+    ) -> std::io::Result<()> {
+        // Replace the status file atomically, so the status subcommand never reads a partial file
+        let seconds_until_flush =
+            next_flush.map(|flush_time| flush_time.saturating_duration_since(Instant::now()).as_secs());
+        let status_json = serde_json::json!({
+            "pid": std::process::id(),
+            "updated_at": chrono::Utc::now().timestamp(),
+            "pending_events": pending_event_count,
+            "seconds_until_flush": seconds_until_flush,
+            "last_batch_time": self.last_batch_time,
+            "tables_seen": self.tables_seen,
+            "paused": is_paused(config),
+        });
+        self.last_write = Some(Instant::now());
+        let tmp_file = format!("{}.tmp", status_file);
+        fs::write(&tmp_file, serde_json::to_string_pretty(&status_json).unwrap())?;
+        fs::rename(&tmp_file, status_file)
+    }
+}
+
+fn flush_pending_batches(
+    table_events: HashMap<String, (Vec<notify::Event>, Instant)>,
+    event_vec: Vec<notify::Event>,
+    hashmap: &TemplateMap,
+    config: &Config,
+) {
+    // Handle every pending batch once before the watcher stops
+    let pending_batches = table_events.into_values().map(|(events, _)| events).chain([event_vec]);
+    for pending_events in pending_batches.filter(|events| !events.is_empty()) {
+        if let Err(e) = handle_csv_file_event(config, hashmap, &pending_events) {
+            error!("Error handling csv file event: {:?}", e);
         }
     }
-    match run_rsync(&rsync_hashmap, &dest_user, &dest_host, &dest_dir, 0) {
-        Ok(_) => {
-            let msg = serde_json::to_string(&rsync_hashmap).unwrap();
-            dbg!(&msg);
-            let dest_addr = format!("{}:50000", dest_host);
-            if let Ok(mut stream) = TcpStream::connect(dest_addr) {
-                let _ = stream.write(&msg.into_bytes());
-            } else {
-                error!("Failed to connect to destination host ({}) on port 50000", dest_host);
-            }
-        },
-        Err(_) => (),
+}
+
+fn print_status(config: &Config, json_output: bool) -> std::io::Result<()> {
+    // Print the watch loop status last written by the running daemon to STATUS_FILE
+    let Some(status_file) = &config.status_file else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "The status subcommand requires STATUS_FILE",
+        ));
+    };
+    let status_data = fs::read_to_string(status_file)?;
+    if json_output {
+        println!("{}", status_data);
+        return Ok(());
+    }
+    let status: serde_json::Value = serde_json::from_str(&status_data)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let age = chrono::Utc::now().timestamp() - status["updated_at"].as_i64().unwrap_or_default();
+    let tables_seen: Vec<&str> = status["tables_seen"]
+        .as_array()
+        .map(|tables| tables.iter().filter_map(serde_json::Value::as_str).collect())
+        .unwrap_or_default();
+    println!("pid:                 {}", status["pid"]);
+    println!("updated:             {} second(s) ago", age);
+    println!("paused:              {}", status["paused"]);
+    println!("pending events:      {}", status["pending_events"]);
+    match status["seconds_until_flush"].as_u64() {
+        Some(seconds) => println!("seconds until flush: {}", seconds),
+        None => println!("seconds until flush: -"),
+    }
+    println!("last batch:          {}", status["last_batch_time"].as_str().unwrap_or("-"));
+    println!("tables seen:         {}", if tables_seen.is_empty() { "-".to_string() } else { tables_seen.join(",") });
+    // The daemon writes the file at least once per CSV_EVENT_WAIT_SECONDS while it runs
+    if age > (config.csv_event_wait_seconds.max(1) * 2 + 5) as i64 {
+        println!("Warning: the status is stale, the daemon may not be running.");
     }
     Ok(())
 }
 
-fn match_col_headers(csv_path: &str, hashmap: &HashMap<String, String>) -> std::io::Result<String> {
-    // Match column header templates and returns the matching table name as a String
-    if Path::new(csv_path).exists() {
-        let csv_file = File::open(csv_path)?;
-        let binding = PathBuf::from(csv_path);
+fn purge_stale_source_files(
+    watch_dirs: &[(PathBuf, Option<(u64, u64)>)],
+    pending_files: &HashSet<&PathBuf>,
+    hashmap: &TemplateMap,
+    config: &Config,
+) {
+    // Delete, or move to SOURCE_PURGE_QUARANTINE_DIR, csv files older than SOURCE_MAX_AGE_HOURS that will never
+    // be transferred because their header matches no template. Matched files (e.g. failed transfers), files
+    // with pending events, unreadable files and any other files are kept.
+    let max_age = Duration::from_secs(config.source_max_age_hours * 3600);
+    for path in list_source_files(watch_dirs, config) {
+        let is_stale = fs::metadata(&path)
+            .and_then(|attr| attr.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > max_age));
+        if !is_stale
+            || path.extension().and_then(|s| s.to_str()) != Some("csv")
+            || !is_included(&path, config)
+            || pending_files.contains(&path)
+        {
+            continue;
+        }
+        let file_path = path.to_str().unwrap();
+        match read_header_line(file_path, config) {
+            Ok(headers) if find_template(&normalize_file_header(&headers, config), hashmap, config).is_none() => (),
+            _ => continue,
+        }
+        match &config.source_purge_quarantine_dir {
+            Some(quarantine_dir) => match archive_file(file_path, Path::new(quarantine_dir), ArchiveMode::Move) {
+                Ok(quarantine_path) => info!(
+                    "Purged unmatched file {} older than {} hours, quarantined to {}",
+                    file_path,
+                    config.source_max_age_hours,
+                    quarantine_path.display()
+                ),
+                Err(e) => error!("Failed to quarantine stale file {}. Keeping it. Error: {}", file_path, e),
+            },
+            None => match fs::remove_file(&path) {
+                Ok(()) => info!(
+                    "Purged unmatched file {} older than {} hours",
+                    file_path, config.source_max_age_hours
+                ),
+                Err(e) => error!("Failed to delete stale file {}. Error: {}", file_path, e),
+            },
+        }
+    }
+}
+
+fn watch_dir_path(src_dir: &str, config: &Config) -> PathBuf {
+    // In the incoming/ready layout only the ready directory is watched
+    match &config.ready_subdir {
+        Some(ready_subdir) => PathBuf::from(src_dir).join(ready_subdir),
+        None => PathBuf::from(src_dir),
+    }
+}
+
+fn list_source_files(watch_dirs: &[(PathBuf, Option<(u64, u64)>)], config: &Config) -> Vec<PathBuf> {
+    // List the files below the watched directories, for scans of the source tree
+    // Directories the daemon moves files into are skipped, even below a watched directory
+    let output_dirs: Vec<&str> = [
+        &config.archive_dir,
+        &config.dead_letter_dir,
+        &config.empty_table_quarantine_dir,
+        &config.invalid_file_quarantine_dir,
+        &config.source_purge_quarantine_dir,
+        &config.upload_log_archive_dir,
+    ]
+    .into_iter()
+    .flatten()
+    .map(String::as_str)
+    .collect();
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut dirs: Vec<PathBuf> = watch_dirs.iter().map(|(watch_dir, _)| watch_dir.clone()).collect();
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read {:?} for scan: {}", dir, e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                if !output_dirs.iter().any(|output_dir| path.starts_with(output_dir)) {
+                    dirs.push(path);
+                }
+                continue;
+            }
+            files.push(path);
+        }
+    }
+    files
+}
+
+fn rotate_upload_logs(watch_dirs: &[(PathBuf, Option<(u64, u64)>)], config: &Config) {
+    // Rotate every non-empty upload.log below the watched directories to upload.log.<timestamp>, then compress it
+    // with gzip (UPLOAD_LOG_COMPRESS) and move it to UPLOAD_LOG_ARCHIVE_DIR, under its directory's absolute path.
+    // The log is renamed while no entry is being written, so only rotated logs are compressed. Failures are logged
+    // and the rotated log is left in place.
+    let timestamp = format_timestamp(chrono::Utc::now(), "%Y%m%d%H%M%S", config.metadata_timezone);
+    for log_file in list_source_files(watch_dirs, config) {
+        if log_file.file_name().and_then(|name| name.to_str()) != Some("upload.log")
+            || fs::metadata(&log_file).map_or(true, |attr| attr.len() == 0)
+        {
+            continue;
+        }
+        let mut rotated_file = PathBuf::from(format!("{}.{}", log_file.display(), timestamp));
+        {
+            let _guard = UPLOAD_LOG_LOCK.lock().unwrap();
+            if let Err(e) = fs::rename(&log_file, &rotated_file) {
+                error!("Failed to rotate {}. Error: {}", log_file.display(), e);
+                continue;
+            }
+        }
+        info!("Rotated {} to {}", log_file.display(), rotated_file.display());
+        if config.upload_log_compress {
+            match Command::new("gzip").arg("-f").arg("--").arg(&rotated_file).output() {
+                Ok(output) if output.status.success() => {
+                    rotated_file = PathBuf::from(format!("{}.gz", rotated_file.display()));
+                }
+                Ok(output) => {
+                    error!(
+                        "Failed to compress {}: {}",
+                        rotated_file.display(),
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    error!("Failed to execute gzip command. Error: {}", e);
+                    continue;
+                }
+            }
+        }
+        if let Some(archive_dir) = &config.upload_log_archive_dir {
+            let log_dir = log_file.parent().unwrap_or(Path::new(""));
+            let archive_log_dir = Path::new(archive_dir).join(log_dir.strip_prefix("/").unwrap_or(log_dir));
+            match archive_file(rotated_file.to_str().unwrap(), &archive_log_dir, ArchiveMode::Move) {
+                Ok(archive_path) => info!("Archived {} to {}", rotated_file.display(), archive_path.display()),
+                Err(e) => error!("Failed to archive {}. Keeping it. Error: {}", rotated_file.display(), e),
+            }
+        }
+    }
+}
+
+fn rescan_source_files(
+    watch_dirs: &[(PathBuf, Option<(u64, u64)>)],
+    pending_files: &HashSet<&PathBuf>,
+    hashmap: &TemplateMap,
+    config: &Config,
+) -> Vec<notify::Event> {
+    // Create events for the files in the watched directories that events may have been lost for, as if they
+    // were just written. Files with pending events and files already handled, which have a metadata file
+    // (e.g. failed transfers), are left out.
+    let mut events: Vec<notify::Event> = Vec::new();
+    for path in list_source_files(watch_dirs, config) {
+        let is_csv_file = match path.extension().and_then(|s| s.to_str()) {
+            Some("csv") => true,
+            None => config.sniff_extensionless && sniff_csv_header(&path, hashmap, config),
+            Some(_) => false,
+        };
+        if !is_csv_file
+            || !is_included(&path, config)
+            || pending_files.contains(&path)
+            || Path::new(&format!("{}.metadata", path.display())).exists()
+        {
+            continue;
+        }
+        events.push(notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(path));
+    }
+    events
+}
+
+fn is_included(file_path: &Path, config: &Config) -> bool {
+    // Check the file name against INCLUDE_GLOBS. Every file is included if no pattern is set.
+    let file_name = file_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    config.include_globs.is_empty() || config.include_globs.iter().any(|glob| glob_match(glob, file_name))
+}
+
+fn glob_match(glob: &str, name: &str) -> bool {
+    // Match a name against a glob where '*' matches any run of characters and '?' any single character
+    let (glob, name): (Vec<char>, Vec<char>) = (glob.chars().collect(), name.chars().collect());
+    let (mut glob_index, mut name_index) = (0, 0);
+    // Position after the last '*' and the name position it currently stands for, to backtrack to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while name_index < name.len() {
+        match glob.get(glob_index) {
+            Some('*') => {
+                backtrack = Some((glob_index + 1, name_index));
+                glob_index += 1;
+            }
+            Some(&c) if c == '?' || c == name[name_index] => {
+                glob_index += 1;
+                name_index += 1;
+            }
+            _ => match backtrack {
+                Some((star_glob_index, star_name_index)) => {
+                    backtrack = Some((star_glob_index, star_name_index + 1));
+                    glob_index = star_glob_index;
+                    name_index = star_name_index + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[glob_index..].iter().all(|&c| c == '*')
+}
+
+fn dir_identity(dir: &Path) -> Option<(u64, u64)> {
+    // Device and inode of a directory, which change when the filesystem it is on is remounted
+    fs::metadata(dir).ok().map(|attr| (attr.dev(), attr.ino()))
+}
+
+fn rewatch_remounted_dirs(watcher: &mut RecommendedWatcher, watch_dirs: &mut [(PathBuf, Option<(u64, u64)>)]) {
+    // Watch again directories whose device or inode changed, since the watch on the old inode no longer
+    // receives events after a remount. Unreachable directories (e.g. while unmounted) are checked again later.
+    for (watch_dir, identity) in watch_dirs.iter_mut() {
+        let current_identity = dir_identity(watch_dir);
+        if current_identity.is_none() || current_identity == *identity {
+            continue;
+        }
+        warn!(
+            "Remount detected for {:?} (device and inode {:?} changed to {:?}). Watching it again.",
+            watch_dir, identity, current_identity
+        );
+        let _ = watcher.unwatch(watch_dir);
+        match watcher.watch(watch_dir, RecursiveMode::Recursive) {
+            Ok(_) => *identity = current_identity,
+            Err(e) => error!("Failed to watch directory {:?} again: {:?}", watch_dir, e),
+        }
+    }
+}
+
+fn is_merged_event(
+    pending_events: &[notify::Event],
+    event: &notify::Event,
+    previous_event_time: Option<Instant>,
+    event_time: Instant,
+    merge_window: Duration,
+) -> bool {
+    // With EVENT_MERGE_WINDOW_MS, an event following the previous event of the same file within the window is
+    // merged into the file's pending event, e.g. the create and data modify events of a single write
+    previous_event_time.is_some_and(|previous_event_time| event_time.duration_since(previous_event_time) < merge_window)
+        && pending_events.iter().any(|e| e.paths[0] == event.paths[0])
+}
+
+#[allow(clippy::too_many_arguments)]
+fn queue_event(
+    event: &notify::Event,
+    event_vec: &mut Vec<notify::Event>,
+    last_event_time: &mut Instant,
+    table_events: &mut HashMap<String, (Vec<notify::Event>, Instant)>,
+    dir_event_times: &mut HashMap<PathBuf, (Instant, Instant)>,
+    file_event_times: &mut HashMap<PathBuf, Instant>,
+    hashmap: &TemplateMap,
+    config: &Config,
+) {
+    // Add an event to the pending batch of its table with PER_TABLE_DEBOUNCE, or to the global batch.
+    // The table is resolved from the header, files whose header doesn't match yet use the global batch.
+    if config.event_merge_window_ms > 0 && event.info() != Some(REQUEUED_EVENT_INFO) {
+        // It still restarts the wait of the batch it is merged into
+        let merge_window = Duration::from_millis(config.event_merge_window_ms);
+        let now = Instant::now();
+        file_event_times.retain(|_, event_time| now.duration_since(*event_time) < merge_window);
+        let previous_event_time = file_event_times.insert(event.paths[0].clone(), now);
+        let is_merged = |events: &[notify::Event]| {
+            is_merged_event(events, event, previous_event_time, now, merge_window)
+        };
+        if let Some((_, table_event_time)) = table_events.values_mut().find(|(events, _)| is_merged(events)) {
+            debug!("Merging event into the pending event of {:?}", event.paths[0]);
+            *table_event_time = now;
+            return;
+        }
+        // With PER_TABLE_DEBOUNCE, a file still in the global batch moves to its table once its header matches
+        if !config.per_table_debounce && is_merged(event_vec) {
+            debug!("Merging event into the pending event of {:?}", event.paths[0]);
+            *last_event_time = now;
+            if config.dir_quiet_seconds > 0 {
+                dir_event_times.entry(event_dir(event)).or_insert((now, now)).1 = now;
+            }
+            return;
+        }
+    }
+    let table_name = if config.per_table_debounce {
+        let known_table = table_events
+            .iter()
+            .find(|(_, (events, _))| events.iter().any(|e| e.paths[0] == event.paths[0]))
+            .map(|(table_name, _)| table_name.clone());
+        known_table.or_else(|| {
+            let csv_headers = read_header_line(event.paths[0].to_str()?, config).ok()?;
+            find_template(&normalize_file_header(&csv_headers, config), hashmap, config)
+                .map(|template| template.table_name.clone())
+        })
+    } else {
+        None
+    };
+    match table_name {
+        Some(table_name) => {
+            // Earlier events of the file, queued before its header was written, now wait with its table
+            event_vec.retain(|e| e.paths[0] != event.paths[0]);
+            let (events, table_event_time) =
+                table_events.entry(table_name).or_insert_with(|| (Vec::new(), Instant::now()));
+            events.push(event.clone());
+            *table_event_time = Instant::now();
+        }
+        None => {
+            event_vec.push(event.clone());
+            *last_event_time = Instant::now();
+            if config.dir_quiet_seconds > 0 {
+                let now = Instant::now();
+                dir_event_times.entry(event_dir(event)).or_insert((now, now)).1 = now;
+            }
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+struct BatchSummary {
+    total_files: usize,
+    table_counts: BTreeMap<String, usize>,
+    succeeded: usize,
+    failed: usize,
+    unmatched: usize,
+    deferred: usize,
+    empty: usize,
+    requeued: usize,
+    succeeded_bytes: u64,
+    table_results: BTreeMap<String, TableMetrics>,
+}
+
+impl BatchSummary {
+    fn record_success(&mut self, table_name: &str) {
+        self.succeeded += 1;
+        self.table_results.entry(table_name.to_string()).or_default().succeeded += 1;
+    }
+
+    fn record_failure(&mut self, table_name: &str, reason: &str) {
+        self.failed += 1;
+        let table_result = self.table_results.entry(table_name.to_string()).or_default();
+        table_result.failed += 1;
+        table_result.last_error = Some((reason.to_string(), chrono::Utc::now().timestamp()));
+    }
+
+    // Files rejected before matching a table (e.g. oversized or unreadable) are counted under UNKNOWN_TABLE
+    fn record_unmatched_failure(&mut self, reason: &str) {
+        self.total_files += 1;
+        self.record_failure(UNKNOWN_TABLE, reason);
+    }
+}
+
+#[derive(Default, Clone)]
+struct TableMetrics {
+    processed: usize,
+    succeeded: usize,
+    failed: usize,
+    // Reason and unix time of the last failure
+    last_error: Option<(String, i64)>,
+}
+
+fn is_paused(config: &Config) -> bool {
+    config.pause_file.as_ref().is_some_and(|pause_file| Path::new(pause_file).exists())
+}
+
+fn drop_excess_events(
+    event_vec: &mut Vec<notify::Event>,
+    table_events: &mut HashMap<String, (Vec<notify::Event>, Instant)>,
+    max_pending_events: usize,
+) -> usize {
+    // Keep the first event of each file, then drop the newest events past max_pending_events, first from the
+    // global batch, then from the table batches
+    let pending_event_count = event_vec.len() + table_events.values().map(|(events, _)| events.len()).sum::<usize>();
+    let mut seen_files: HashSet<PathBuf> = HashSet::new();
+    for events in [&mut *event_vec].into_iter().chain(table_events.values_mut().map(|(events, _)| events)) {
+        events.retain(|event| seen_files.insert(event.paths[0].clone()));
+    }
+    let mut excess = seen_files.len().saturating_sub(max_pending_events);
+    for events in [&mut *event_vec].into_iter().chain(table_events.values_mut().map(|(events, _)| events)) {
+        let removed = excess.min(events.len());
+        events.truncate(events.len() - removed);
+        excess -= removed;
+    }
+    table_events.retain(|_, (events, _)| !events.is_empty());
+    pending_event_count - event_vec.len() - table_events.values().map(|(events, _)| events.len()).sum::<usize>()
+}
+
+fn hold_events(event_vec: &[notify::Event]) -> Vec<notify::Event> {
+    // Keep one event per file, so held events don't grow with repeated writes to the same files
+    let mut held_events: Vec<notify::Event> = Vec::new();
+    for event in event_vec {
+        if !held_events.iter().any(|e| e.paths[0] == event.paths[0]) {
+            held_events.push(event.clone());
+        }
+    }
+    held_events
+}
+
+fn handle_csv_file_event(
+    config: &Config,
+    hashmap: &TemplateMap,
+    event_vec: &[notify::Event],
+) -> std::io::Result<(Vec<notify::Event>, BatchSummary)> {
+    // Handle csv file events, returning the events deferred to the next cycle and the batch summary
+    let mut event_vec: Vec<notify::Event> = event_vec
+        .iter()
+        .filter(|event| {
+            if event.paths.is_empty() {
+                debug!("Skipping event without paths: {:?}", event);
+            }
+            !event.paths.is_empty()
+        })
+        .cloned()
+        .collect();
+    let batch_start_time = chrono::Local::now();
+    let batch_timer = Instant::now();
+    let _batch_id_scope = BatchIdScope::enter(config.batch_id_format.map(|format| new_batch_id(format, config)));
+    let mut summary = BatchSummary::default();
+    if let Some(pause_file) = &config.pause_file {
+        if is_paused(config) {
+            let deferred_events = hold_events(&event_vec);
+            summary.deferred = deferred_events.len();
+            // Held batches are retried every cycle, only the first one of a pause is logged
+            if !PAUSED.swap(true, Ordering::Relaxed) {
+                warn!("Processing paused, {} exists.", pause_file);
+                info!("Paused. Holding {} file(s) until {} is removed.", summary.deferred, pause_file);
+            } else {
+                debug!("Paused. Holding {} file(s) until {} is removed.", summary.deferred, pause_file);
+            }
+            return Ok((deferred_events, summary));
+        }
+        if PAUSED.swap(false, Ordering::Relaxed) {
+            info!("{} removed, resuming processing.", pause_file);
+        }
+    }
+    if let Some(allowed_hours) = &config.allowed_hours {
+        if !allowed_hours.contains(zone_now(config.metadata_timezone).time()) {
+            // Hold all events, untouched, until a later cycle falls within the transfer window
+            let deferred_events = hold_events(&event_vec);
+            summary.deferred = deferred_events.len();
+            info!(
+                "Outside ALLOWED_HOURS {}-{}. Holding {} file(s) until the window opens.",
+                allowed_hours.start.format("%H:%M"),
+                allowed_hours.end.format("%H:%M"),
+                summary.deferred
+            );
+            return Ok((deferred_events, summary));
+        }
+    }
+    info!(
+        "Handling CSV file events. Total event count: {:?}",
+        event_vec.len()
+    );
+    // Files of tables left over by the previous cycle come first, so their tables are among those handled
+    if config.max_tables_per_cycle > 0 {
+        if let Some(table_limit_deferred) = TABLE_LIMIT_DEFERRED.lock().unwrap().as_ref() {
+            event_vec.sort_by_key(|event| !table_limit_deferred.contains(&event.paths[0]));
+        }
+    }
+    let mut limited_tables: BTreeSet<String> = BTreeSet::new();
+    let mut limited_files: HashSet<PathBuf> = HashSet::new();
+    // debug!("Event Vec: {:?}", event_vec);
+    /*
+    Rsync hashmap structure:
+    {
+        "table_name": {
+            "src_files": [src_file...],
+            "metadata_files": [metadata_file...]
+            "uploaded_by": [username...]
+            "dest_dirs": [dest_dir...]
+            "md5_files": [md5_file...] (if GENERATE_MD5_SIDECAR is enabled)
+            "upload_files": [upload_file...] (if COLUMN_RENAME_MAP has renames for the table)
+        }
+    }
+     */
+    let mut rsync_hashmap: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
+    let mut deferred_events: Vec<notify::Event> = Vec::new();
+    let mut oversized_files: Vec<PathBuf> = Vec::new();
+    let mut empty_files: Vec<PathBuf> = Vec::new();
+    let mut invalid_files: Vec<PathBuf> = Vec::new();
+    // Files checked for growth in this batch, each is checked once whatever its number of events
+    let mut growth_checked_files: HashSet<PathBuf> = HashSet::new();
+    let mut hot_files: HashSet<PathBuf> = HashSet::new();
+    // Other events of re-queued files are handled by their re-queued event, which keeps the file name
+    let requeued_paths: HashSet<&PathBuf> = event_vec
+        .iter()
+        .filter(|event| event.info() == Some(REQUEUED_EVENT_INFO))
+        .map(|event| &event.paths[0])
+        .collect();
+    for event in event_vec.iter() {
+        start_file_timer(&event.paths[0]);
+        if event.info() != Some(REQUEUED_EVENT_INFO) && requeued_paths.contains(&event.paths[0]) {
+            continue;
+        }
+        let src_file_path = event.paths[0].to_str().unwrap();
+        let src_file_basename = event.paths[0].file_name().unwrap().to_str().unwrap();
+        if config.hot_file_checks > 0
+            && growth_checked_files.insert(event.paths[0].clone())
+            && is_hot_file(&event.paths[0], config)
+        {
+            hot_files.insert(event.paths[0].clone());
+        }
+        if hot_files.contains(&event.paths[0]) {
+            // Checked again next cycle, so a file that stopped growing is transferred without a new event
+            if !deferred_events.iter().any(|e| e.paths[0] == event.paths[0]) {
+                deferred_events.push(event.clone());
+                summary.deferred += 1;
+            }
+            continue;
+        }
+        if is_file_too_young(src_file_path, config.min_file_age_seconds) {
+            info!(
+                "File {:?} is younger than {} seconds. Deferring to next cycle.",
+                src_file_basename, config.min_file_age_seconds
+            );
+            if !deferred_events.iter().any(|e| e.paths[0] == event.paths[0]) {
+                deferred_events.push(event.clone());
+                summary.deferred += 1;
+            }
+            continue;
+        }
+        if config.respect_writer_locks && is_file_locked(src_file_path) {
+            info!(
+                "File {:?} is exclusively locked by its writer. Deferring to next cycle.",
+                src_file_basename
+            );
+            if !deferred_events.iter().any(|e| e.paths[0] == event.paths[0]) {
+                deferred_events.push(event.clone());
+                summary.deferred += 1;
+            }
+            continue;
+        }
+        if let Some(file_size) = file_too_large(src_file_path, config.max_file_bytes) {
+            // Report each oversized file once even if it has several events in this batch
+            if !oversized_files.contains(&event.paths[0]) {
+                oversized_files.push(event.paths[0].clone());
+                let reason = format!(
+                    "File too large: {} bytes exceeds MAX_FILE_BYTES={}",
+                    file_size, config.max_file_bytes
+                );
+                summary.record_unmatched_failure(&reason);
+                error!("Skipping file {:?}. {}", src_file_basename, reason);
+                match &event.paths[0].parent() {
+                    Some(log_dir) => log_upload_status(
+                        log_dir.to_str().unwrap(),
+                        src_file_basename,
+                        "",
+                        Some(&reason),
+                        config,
+                    ),
+                    None => error!("Failed to get parent directory of source file."),
+                }
+            }
+            continue;
+        }
+        let match_result = match_col_headers(src_file_path, hashmap, config);
+        match match_result {
+            Ok(table_name) => {
+                if table_name.is_empty() {
+                    // Events for files already renamed earlier in this batch no longer exist
+                    if event.paths[0].exists() {
+                        if config.unmatched_is_error {
+                            summary.record_unmatched_failure(NO_MATCHING_HEADERS_REASON);
+                        } else {
+                            summary.total_files += 1;
+                            summary.unmatched += 1;
+                        }
+                    }
+                } else if let Some(reason) = invalid_utf8_reason(src_file_path, config)
+                    .or_else(|| type_violation_reason(src_file_path, &table_name, config))
+                {
+                    // Report each invalid file once even if it has several events in this batch
+                    if !invalid_files.contains(&event.paths[0]) {
+                        invalid_files.push(event.paths[0].clone());
+                        summary.total_files += 1;
+                        *summary.table_counts.entry(table_name.clone()).or_default() += 1;
+                        summary.record_failure(&table_name, &reason);
+                        error!("Rejecting file {:?} for table {}. {}", src_file_basename, table_name, reason);
+                        if let Some(log_dir) = event.paths[0].parent() {
+                            log_upload_status(
+                                log_dir.to_str().unwrap(),
+                                src_file_basename,
+                                &table_name,
+                                Some(&reason),
+                                config,
+                            );
+                        }
+                        if let Some(quarantine_dir) = &config.invalid_file_quarantine_dir {
+                            match archive_file(src_file_path, Path::new(quarantine_dir), ArchiveMode::Move) {
+                                Ok(quarantine_path) => {
+                                    info!("Quarantined {} to {}", src_file_path, quarantine_path.display())
+                                }
+                                Err(e) => error!("Failed to quarantine {}. Keeping it. Error: {}", src_file_path, e),
+                            }
+                        }
+                    }
+                } else if config.skip_empty_tables && is_empty_table(src_file_path, config) {
+                    // Report each empty file once even if it has several events in this batch
+                    if !empty_files.contains(&event.paths[0]) {
+                        empty_files.push(event.paths[0].clone());
+                        summary.total_files += 1;
+                        summary.empty += 1;
+                        info!(
+                            "Skipping file {:?} for table {}: no data rows",
+                            src_file_basename, table_name
+                        );
+                        handle_empty_table(src_file_path, config);
+                    }
+                } else if config.max_tables_per_cycle > 0
+                    && !rsync_hashmap.contains_key(&table_name)
+                    && rsync_hashmap.len() >= config.max_tables_per_cycle
+                {
+                    // Held untouched, like files deferred by the minimum age check
+                    if limited_files.insert(event.paths[0].clone()) {
+                        deferred_events.push(event.clone());
+                        summary.deferred += 1;
+                    }
+                    limited_tables.insert(table_name);
+                } else {
+                    summary.total_files += 1;
+                    *summary.table_counts.entry(table_name.clone()).or_default() += 1;
+                    let username = get_file_owner(src_file_path, config).unwrap();
+                    let src_file_with_suffix = if event.info() == Some(REQUEUED_EVENT_INFO) {
+                        src_file_path.to_string()
+                    } else {
+                        suffix_file_name(src_file_path, config)?
+                    };
+                    move_file_timer(&event.paths[0], Path::new(&src_file_with_suffix));
+                    info!("Source file with suffix: {:?}", src_file_with_suffix);
+                    let metadata_file = match create_metadata_file(&src_file_with_suffix, &table_name, config) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            error!("Error creating metadata file: {:?}", e);
+                            String::new()
+                        }
+                    };
+                    tag_source_file(&src_file_with_suffix, config);
+                    // The file actually uploaded, a copy if its columns are renamed or its line endings normalized
+                    let upload_file = match prepare_upload_file(&src_file_with_suffix, &table_name, config) {
+                        Ok(upload_file) => upload_file,
+                        Err(reason) => {
+                            summary.record_failure(&table_name, &reason);
+                            if let Some(log_dir) = event.paths[0].parent() {
+                                log_upload_status(
+                                    log_dir.to_str().unwrap(),
+                                    src_file_basename,
+                                    &table_name,
+                                    Some(&reason),
+                                    config,
+                                );
+                            }
+                            continue;
+                        }
+                    };
+                    let dest_table_dir = resolve_dest_dir(src_file_path, &table_name, config);
+                    let table_entry = rsync_hashmap.entry(table_name).or_default();
+                    if config.generate_md5_sidecar {
+                        let md5_file = match create_md5_sidecar(
+                            &src_file_with_suffix,
+                            upload_file.as_deref().unwrap_or(&src_file_with_suffix),
+                        ) {
+                            Ok(file) => file,
+                            Err(e) => {
+                                error!("Error creating md5 sidecar file: {:?}", e);
+                                String::new()
+                            }
+                        };
+                        table_entry
+                            .entry("md5_files".to_string())
+                            .or_default()
+                            .push(md5_file);
+                    }
+                    if let Some(upload_file) = upload_file {
+                        table_entry
+                            .entry("upload_files".to_string())
+                            .or_default()
+                            .push(upload_file);
+                    }
+                    table_entry
+                        .entry("src_files".to_string())
+                        .or_default()
+                        .push(src_file_with_suffix);
+                    table_entry
+                        .entry("metadata_files".to_string())
+                        .or_default()
+                        .push(metadata_file);
+                    table_entry
+                        .entry("uploaded_by".to_string())
+                        .or_default()
+                        .push(username);
+                    table_entry
+                        .entry("dest_dirs".to_string())
+                        .or_default()
+                        .push(dest_table_dir);
+                }
+            },
+            Err(e) => {
+                summary.record_unmatched_failure(&e.to_string());
+                error!("Error matching column headers: {:?}", e);
+                match &event.paths[0].parent() {
+                    Some(log_dir) => log_upload_status(
+                        log_dir.to_str().unwrap(),
+                        src_file_basename,
+                        "",
+                        Some(&e.to_string()),
+                        config,
+                    ),
+                    None => error!("Failed to get parent directory of source file."),
+                }
+            }
+        }
+    }
+    if !limited_tables.is_empty() {
+        info!(
+            "MAX_TABLES_PER_CYCLE ({}) reached. Deferring {} file(s) of {} table(s) to the next cycle: {:?}",
+            config.max_tables_per_cycle,
+            limited_files.len(),
+            limited_tables.len(),
+            limited_tables
+        );
+    }
+    if config.max_tables_per_cycle > 0 {
+        let mut table_limit_deferred = TABLE_LIMIT_DEFERRED.lock().unwrap();
+        let table_limit_deferred = table_limit_deferred.get_or_insert_with(HashSet::new);
+        let handled_paths: HashSet<&PathBuf> = event_vec.iter().map(|event| &event.paths[0]).collect();
+        table_limit_deferred.retain(|path| !handled_paths.contains(path));
+        table_limit_deferred.extend(limited_files);
+    }
+    let batch_timing = (batch_start_time, batch_timer);
+    let batch_tx = TRANSFER_PIPELINE.lock().unwrap().clone();
+    if let Some(batch_tx) = batch_tx {
+        // The transfer worker reports the batch. Queuing blocks while TRANSFER_PIPELINE_DEPTH batches are waiting.
+        let batch = PreparedBatch {
+            rsync_hashmap,
+            summary: summary.clone(),
+            timing: batch_timing,
+            batch_id: current_batch_id(),
+        };
+        if let Err(e) = batch_tx.send(batch) {
+            error!("Transfer worker stopped, {} table(s) of the batch are not transferred", e.0.rsync_hashmap.len());
+        }
+        return Ok((deferred_events, summary));
+    }
+    deferred_events.extend(transfer_batch(&rsync_hashmap, &mut summary, batch_timing, config));
+    Ok((deferred_events, summary))
+}
+
+fn transfer_batch(
+    rsync_hashmap: &HashMap<String, HashMap<String, Vec<String>>>,
+    summary: &mut BatchSummary,
+    (batch_start_time, batch_timer): (chrono::DateTime<chrono::Local>, Instant),
+    config: &Config,
+) -> Vec<notify::Event> {
+    // Transfer a prepared batch and report it, returning the events of files to handle again in the next cycle
+    let mut requeued_events: Vec<notify::Event> = Vec::new();
+    let rsync_result = run_rsync(rsync_hashmap, config, summary);
+    if let Ok(requeued_files) = &rsync_result {
+        // Files changed by their writer during the transfer are handled again in the next cycle,
+        // keeping their suffixed name
+        for requeued_file in requeued_files {
+            let requeued_event = notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(PathBuf::from(requeued_file))
+                .set_info(REQUEUED_EVENT_INFO);
+            requeued_events.push(requeued_event);
+        }
+    }
+    if rsync_result.is_ok() {
+        let msg = serde_json::to_string(rsync_hashmap).unwrap();
+        debug!("Batch notification: {}", msg);
+        let dest_addr = format!("{}:50000", config.dest_host);
+        if let Ok(mut stream) = TcpStream::connect(dest_addr) {
+            let _ = stream.write(&msg.into_bytes());
+        } else {
+            error!("Failed to connect to destination host ({}) on port 50000", config.dest_host);
+        }
+    }
+    log_batch_summary(summary, batch_start_time, batch_timer.elapsed());
+    notify_batch_success(summary, batch_start_time, batch_timer.elapsed(), config);
+    if let Some(metrics_file) = &config.metrics_file {
+        if let Err(e) = update_table_metrics(summary, metrics_file) {
+            error!("Failed to write metrics file {}: {}", metrics_file, e);
+        }
+    }
+    requeued_events
+}
+
+fn resolve_dest_dir(src_file_path: &str, table_name: &str, config: &Config) -> String {
+    // Resolve remote table directory, prefixed by the subdirectory mapped to the file's source directory.
+    // The longest matching source directory wins if source directories are nested.
+    let dest_prefix = config
+        .dest_subdir_map
+        .iter()
+        .filter(|(src_dir, _)| Path::new(src_file_path).starts_with(src_dir))
+        .max_by_key(|(src_dir, _)| src_dir.len())
+        .map(|(_, prefix)| prefix.as_str())
+        .unwrap_or("");
+    PathBuf::from(&config.dest_dir)
+        .join(dest_prefix)
+        .join(table_name)
+        .display()
+        .to_string()
+}
+
+fn log_batch_summary(
+    summary: &BatchSummary,
+    batch_start_time: chrono::DateTime<chrono::Local>,
+    batch_duration: Duration,
+) {
+    // Log a single line summarizing the outcome of a batch
+    let time_format = "%Y-%m-%d %H:%M:%S";
+    info!(
+        "Batch summary: start={} end={} duration={:.2}s total_files={} tables={:?} succeeded={} failed={} unmatched={} deferred={} empty={} requeued={}",
+        batch_start_time.format(time_format),
+        chrono::Local::now().format(time_format),
+        batch_duration.as_secs_f64(),
+        summary.total_files,
+        summary.table_counts,
+        summary.succeeded,
+        summary.failed,
+        summary.unmatched,
+        summary.deferred,
+        summary.empty,
+        summary.requeued,
+    );
+}
+
+fn update_table_metrics(summary: &BatchSummary, metrics_file: &str) -> std::io::Result<()> {
+    // Add the batch's per table results to the counters since startup and rewrite the metrics file in the
+    // Prometheus text format (e.g. for the node_exporter textfile collector). It is replaced atomically.
+    let mut table_metrics = TABLE_METRICS.lock().unwrap();
+    for (table_name, processed) in &summary.table_counts {
+        table_metrics.entry(table_name.clone()).or_default().processed += processed;
+    }
+    for (table_name, table_result) in &summary.table_results {
+        let metrics = table_metrics.entry(table_name.clone()).or_default();
+        metrics.succeeded += table_result.succeeded;
+        metrics.failed += table_result.failed;
+        if table_result.last_error.is_some() {
+            metrics.last_error = table_result.last_error.clone();
+        }
+    }
+    // Label values escape backslashes, quotes and newlines. Only the first line of a reason is kept.
+    let label = |value: &str| {
+        let value: String = value.lines().next().unwrap_or_default().trim().chars().take(200).collect();
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    };
+    let mut metrics_data = String::new();
+    let counters = [
+        ("processed", "Matched files handled"),
+        ("succeeded", "Files transferred successfully"),
+        ("failed", "Files that failed"),
+    ];
+    for (name, help) in counters {
+        metrics_data.push_str(&format!("# HELP rsync_csv_table_files_{}_total {} per table.\n", name, help));
+        metrics_data.push_str(&format!("# TYPE rsync_csv_table_files_{}_total counter\n", name));
+        for (table_name, metrics) in table_metrics.iter() {
+            let value = match name {
+                "processed" => metrics.processed,
+                "succeeded" => metrics.succeeded,
+                _ => metrics.failed,
+            };
+            metrics_data.push_str(&format!(
+                "rsync_csv_table_files_{}_total{{table=\"{}\"}} {}\n",
+                name,
+                label(table_name),
+                value
+            ));
+        }
+    }
+    metrics_data.push_str("# HELP rsync_csv_table_last_error_timestamp_seconds Unix time of the last failure per table.\n");
+    metrics_data.push_str("# TYPE rsync_csv_table_last_error_timestamp_seconds gauge\n");
+    for (table_name, metrics) in table_metrics.iter() {
+        if let Some((_, error_time)) = &metrics.last_error {
+            metrics_data.push_str(&format!(
+                "rsync_csv_table_last_error_timestamp_seconds{{table=\"{}\"}} {}\n",
+                label(table_name),
+                error_time
+            ));
+        }
+    }
+    metrics_data.push_str("# HELP rsync_csv_table_last_error_info Reason of the last failure per table.\n");
+    metrics_data.push_str("# TYPE rsync_csv_table_last_error_info gauge\n");
+    for (table_name, metrics) in table_metrics.iter() {
+        if let Some((reason, _)) = &metrics.last_error {
+            metrics_data.push_str(&format!(
+                "rsync_csv_table_last_error_info{{table=\"{}\",reason=\"{}\"}} 1\n",
+                label(table_name),
+                label(reason)
+            ));
+        }
+    }
+    let tmp_file = format!("{}.tmp", metrics_file);
+    fs::write(&tmp_file, metrics_data)?;
+    fs::rename(&tmp_file, metrics_file)
+}
+
+fn notify_batch_success(
+    summary: &BatchSummary,
+    batch_start_time: chrono::DateTime<chrono::Local>,
+    batch_duration: Duration,
+    config: &Config,
+) {
+    // Post the batch summary to SUCCESS_WEBHOOK_URL after a batch without failures that reached the
+    // file or byte threshold. The request runs in the background and its failures are only logged.
+    let Some(webhook_url) = &config.success_webhook_url else {
+        return;
+    };
+    // Without thresholds every successful batch is reported
+    let large_batch = match (config.success_webhook_min_files, config.success_webhook_min_bytes) {
+        (0, 0) => true,
+        (min_files, min_bytes) => {
+            (min_files > 0 && summary.succeeded >= min_files)
+                || (min_bytes > 0 && summary.succeeded_bytes >= min_bytes)
+        }
+    };
+    if summary.failed > 0 || summary.succeeded == 0 || !large_batch {
+        return;
+    }
+    let mut payload = serde_json::json!({
+        "status": "succeeded",
+        "start": batch_start_time.to_rfc3339(),
+        "end": chrono::Local::now().to_rfc3339(),
+        "duration_seconds": batch_duration.as_secs_f64(),
+        "total_files": summary.total_files,
+        "tables": summary.table_counts,
+        "succeeded": summary.succeeded,
+        "succeeded_bytes": summary.succeeded_bytes,
+        "unmatched": summary.unmatched,
+        "deferred": summary.deferred,
+        "empty": summary.empty,
+    });
+    if let Some(hostname) = &config.source_hostname {
+        payload["host"] = serde_json::json!(hostname);
+    }
+    if let Some(batch_id) = current_batch_id() {
+        payload["batch_id"] = serde_json::json!(batch_id);
+    }
+    post_webhook(webhook_url, payload, "batch success");
+}
+
+fn post_webhook(webhook_url: &str, payload: serde_json::Value, description: &'static str) {
+    // POST a JSON payload with curl in the background. Failures are only logged.
+    let webhook_url = webhook_url.to_string();
+    let batch_id = current_batch_id();
+    thread::spawn(move || {
+        let _batch_id_scope = BatchIdScope::enter(batch_id);
+        let output = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--max-time", "10"])
+            .args(["-H", "Content-Type: application/json"])
+            .arg("--data-binary")
+            .arg(payload.to_string())
+            .arg(&webhook_url)
+            .output();
+        match output {
+            Ok(output) if output.status.success() => info!("Sent {} notification", description),
+            Ok(output) => warn!(
+                "Failed to send {} notification: {}",
+                description,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => warn!("Failed to execute curl command. Error: {}", e),
+        }
+    });
+}
+
+fn is_hot_file(file_path: &Path, config: &Config) -> bool {
+    // Check whether a file is hot, i.e. kept growing at more than HOT_FILE_CHECKS consecutive stability checks
+    // (one per batch the file has events in), like a live log written with a .csv extension.
+    // A file that stops growing or shrinks is no longer hot. Becoming hot is logged and alerted once.
+    let Ok(size) = fs::metadata(file_path).map(|attr| attr.len()) else {
+        return false;
+    };
+    let growth_count = {
+        let mut file_growth = FILE_GROWTH.lock().unwrap();
+        // Files that were transferred, renamed or removed are forgotten
+        file_growth.retain(|path, _| path.exists());
+        let (last_size, growth_count) = file_growth.entry(file_path.to_path_buf()).or_insert((size, 0));
+        if size <= *last_size && *growth_count > config.hot_file_checks {
+            info!("Hot file {:?} stopped growing ({} bytes), handling it again", file_path, size);
+        }
+        *growth_count = if size > *last_size { *growth_count + 1 } else { 0 };
+        *last_size = size;
+        *growth_count
+    };
+    if growth_count <= config.hot_file_checks {
+        return false;
+    }
+    if growth_count > config.hot_file_checks + 1 {
+        debug!("Hot file {:?} is still growing ({} bytes), skipping it", file_path, size);
+        return true;
+    }
+    warn!(
+        "File {:?} kept growing across {} checks ({} bytes), skipping it as a hot file until it stops growing",
+        file_path, growth_count, size
+    );
+    if let Some(webhook_url) = &config.hot_file_webhook_url {
+        let mut payload = serde_json::json!({
+            "status": "hot_file",
+            "file": file_path.display().to_string(),
+            "size": size,
+            "checks": growth_count,
+        });
+        if let Some(hostname) = &config.source_hostname {
+            payload["host"] = serde_json::json!(hostname);
+        }
+        if let Some(batch_id) = current_batch_id() {
+            payload["batch_id"] = serde_json::json!(batch_id);
+        }
+        post_webhook(webhook_url, payload, "hot file");
+    }
+    true
+}
+
+struct BatchLogger(SimpleLogger);
+
+impl log::Log for BatchLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        // Log lines written while a batch is handled are prefixed with its ID
+        match current_batch_id() {
+            Some(batch_id) => self.0.log(
+                &log::Record::builder()
+                    .metadata(record.metadata().clone())
+                    .args(format_args!("[batch {}] {}", batch_id, record.args()))
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.0.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+struct BatchIdScope;
+
+impl BatchIdScope {
+    fn enter(batch_id: Option<String>) -> BatchIdScope {
+        // Set the batch ID of the current thread until the returned scope is dropped
+        BATCH_ID.with(|current| *current.borrow_mut() = batch_id);
+        BatchIdScope
+    }
+}
+
+impl Drop for BatchIdScope {
+    fn drop(&mut self) {
+        BATCH_ID.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+fn current_batch_id() -> Option<String> {
+    BATCH_ID.with(|current| current.borrow().clone())
+}
+
+fn new_batch_id(format: BatchIdFormat, config: &Config) -> String {
+    // Generate a batch ID: a random (version 4) UUID, or the current time with a counter since startup
+    static BATCH_COUNTER: AtomicUsize = AtomicUsize::new(1);
+    match format {
+        BatchIdFormat::Uuid => uuid::Uuid::new_v4().to_string(),
+        BatchIdFormat::Timestamp => format!(
+            "{}-{:04}",
+            format_timestamp(chrono::Utc::now(), "%Y%m%dT%H%M%S", config.metadata_timezone),
+            BATCH_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ),
+    }
+}
+
+fn is_file_too_young(file_path: &str, min_file_age_seconds: u64) -> bool {
+    // Check if file was last modified less than the configured minimum age ago
+    if min_file_age_seconds == 0 {
+        return false;
+    }
+    match fs::metadata(file_path).and_then(|attr| attr.modified()) {
+        Ok(modified) => match modified.elapsed() {
+            Ok(age) => age < Duration::from_secs(min_file_age_seconds),
+            // Modification time is in the future, treat file as still being written
+            Err(_) => true,
+        },
+        Err(_) => false,
+    }
+}
+
+fn is_file_locked(file_path: &str) -> bool {
+    // Check if another process holds an exclusive advisory (flock) lock on the file
+    // by trying to take a shared lock without blocking. The lock is released when the file is closed.
+    match File::open(file_path).map(|file| file.try_lock_shared()) {
+        Ok(Err(std::fs::TryLockError::WouldBlock)) => true,
+        Ok(Err(std::fs::TryLockError::Error(e))) => {
+            debug!("Failed to check lock on {:?}: {}", file_path, e);
+            false
+        }
+        Ok(Ok(())) => false,
+        Err(_) => false,
+    }
+}
+
+fn is_empty_table(file_path: &str, config: &Config) -> bool {
+    // Check if a matched file has a header but no data rows. Unreadable files are not treated as empty
+    // so the transfer reports the actual error.
+    match count_data_rows(file_path, config) {
+        Ok(rows) => rows == 0,
+        Err(e) => {
+            debug!("Failed to count data rows of {:?}: {}", file_path, e);
+            false
+        }
+    }
+}
+
+fn invalid_utf8_reason(file_path: &str, config: &Config) -> Option<String> {
+    // With VALIDATE_UTF8, check the first VALIDATE_UTF8_BYTES of a matched file for NUL bytes and invalid UTF-8,
+    // which indicate a corrupted export behind a valid header. Returns the reason to reject the file.
+    if !config.validate_utf8 {
+        return None;
+    }
+    let mut content = Vec::new();
+    let read_result =
+        File::open(file_path).and_then(|file| file.take(config.validate_utf8_bytes).read_to_end(&mut content));
+    if let Err(e) = read_result {
+        debug!("Failed to read {:?} for UTF-8 validation: {}", file_path, e);
+        return None;
+    }
+    if let Some(position) = content.iter().position(|&byte| byte == 0) {
+        return Some(format!("Invalid content: NUL byte at offset {}", position));
+    }
+    match std::str::from_utf8(&content) {
+        // A multi-byte character cut off at the end of the checked bytes is not an error
+        Err(e) if e.error_len().is_some() => {
+            Some(format!("Invalid content: invalid UTF-8 at offset {}", e.valid_up_to()))
+        }
+        _ => None,
+    }
+}
+
+fn type_violation_reason(file_path: &str, table_name: &str, config: &Config) -> Option<String> {
+    // With VALIDATE_TYPES, stream the data rows of a matched file and check the columns listed for its table in
+    // TABLE_COLUMN_TYPES. Returns the reason to reject the file at the first value of the wrong type.
+    if !config.validate_types {
+        return None;
+    }
+    let (_, column_types) = config.table_column_types.iter().find(|(table, _)| table == table_name)?;
+    let reader = match File::open(file_path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            debug!("Failed to read {:?} for type validation: {}", file_path, e);
+            return None;
+        }
+    };
+    let mut lines = reader.lines().skip(config.header_row_index);
+    let header = lines.next().and_then(Result::ok).unwrap_or_default();
+    let header_columns: Vec<String> = split_csv_fields(normalize_file_header(&header, config).as_ref());
+    let checked_columns: Vec<(usize, &String, ColumnType)> = column_types
+        .iter()
+        .filter_map(|(column, column_type)| {
+            let index = header_columns.iter().position(|header_column| header_column.trim() == column);
+            if index.is_none() {
+                warn!("Typed column {} not found in header of {}, it is not checked", column, file_path);
+            }
+            Some((index?, column, *column_type))
+        })
+        .collect();
+    for (row_index, line) in lines.enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to read {:?} for type validation: {}", file_path, e);
+                return None;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_csv_fields(&line);
+        for (index, column, column_type) in &checked_columns {
+            let value = fields.get(*index).map_or("", |value| value.trim());
+            let valid = if value.is_empty() { config.types_allow_empty } else { column_type.accepts(value) };
+            if !valid {
+                // Lines are numbered from 1, including the header and the lines before it
+                return Some(format!(
+                    "Type violation at line {}, column {}: expected {}, got {:?}",
+                    config.header_row_index + row_index + 2,
+                    column,
+                    format!("{:?}", column_type).to_lowercase(),
+                    value
+                ));
+            }
+        }
+    }
+    None
+}
+
+fn handle_empty_table(src_file: &str, config: &Config) {
+    // Keep, delete or quarantine a skipped file without data rows, per EMPTY_TABLE_ACTION
+    match config.empty_table_action {
+        EmptyTableAction::Keep => (),
+        EmptyTableAction::Delete => remove_file_if_exists(src_file),
+        EmptyTableAction::Quarantine => {
+            let quarantine_dir = config.empty_table_quarantine_dir.as_deref().unwrap();
+            match archive_file(src_file, Path::new(quarantine_dir), ArchiveMode::Move) {
+                Ok(quarantine_path) => info!("Quarantined {} to {}", src_file, quarantine_path.display()),
+                Err(e) => error!("Failed to quarantine {}. Keeping it. Error: {}", src_file, e),
+            }
+        }
+    }
+}
+
+fn file_too_large(file_path: &str, max_file_bytes: u64) -> Option<u64> {
+    // Return the file size if it exceeds the configured maximum, so oversized files are skipped
+    // before any hashing, row counting or transfer
+    if max_file_bytes == 0 {
+        return None;
+    }
+    fs::metadata(file_path)
+        .map(|attr| attr.len())
+        .ok()
+        .filter(|file_size| *file_size > max_file_bytes)
+}
+
+fn sniff_csv_header(file_path: &Path, hashmap: &TemplateMap, config: &Config) -> bool {
+    // Check if the header line of a file without extension matches a template header.
+    // Only the first SNIFF_MAX_LINE_BYTES are read so large binary files are not scanned.
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(e) => {
+            debug!("Failed to open {:?} for sniffing: {}", file_path, e);
+            return false;
+        }
+    };
+    let mut reader = BufReader::new(file).take(SNIFF_MAX_LINE_BYTES);
+    let mut header_line: Vec<u8> = Vec::new();
+    for _ in 0..=config.header_row_index {
+        header_line.clear();
+        if reader.read_until(b'\n', &mut header_line).is_err() || !header_line.ends_with(b"\n") {
+            return false;
+        }
+    }
+    match String::from_utf8(header_line) {
+        Ok(line) => {
+            find_template(&normalize_file_header(&line, config), hashmap, config).is_some()
+        }
+        Err(_) => false,
+    }
+}
+
+fn match_col_headers(
+    csv_path: &str,
+    hashmap: &TemplateMap,
+    config: &Config,
+) -> std::io::Result<String> {
+    match_col_headers_using(csv_path, hashmap, config, read_header_line)
+}
+
+fn match_col_headers_using(
+    csv_path: &str,
+    hashmap: &TemplateMap,
+    config: &Config,
+    mut read_header: impl FnMut(&str, &Config) -> std::io::Result<String>,
+) -> std::io::Result<String> {
+    // Match column header templates and returns the matching table name as a String
+    if Path::new(csv_path).exists() {
+        let binding = PathBuf::from(csv_path);
         let csv_file_basename = binding.file_name().unwrap().to_str().unwrap();
-        let reader = BufReader::new(csv_file);
-        let csv_headers = reader.lines().next().unwrap_or_else(|| Ok(String::new()))?;
+        // The state is taken before the read, so a write landing right after it is seen as a change
+        let mut file_state_before = file_state(csv_path);
+        let mut csv_headers = read_header(csv_path, config)?;
         info!("CSV Headers: {:?}", csv_headers);
-        match hashmap.get(csv_headers.trim_end_matches(",")) {
-            Some(table_name) => {
+        // The header may not be fully flushed yet if the file is still being written.
+        // Re-read it a bounded number of times as long as the file keeps changing.
+        let mut retry_count = 0;
+        while retry_count < config.header_match_retries
+            && find_template(&normalize_file_header(&csv_headers, config), hashmap, config).is_none()
+        {
+            thread::sleep(Duration::from_millis(config.header_match_retry_interval_ms));
+            let file_state_now = file_state(csv_path);
+            if file_state_now == file_state_before {
+                break;
+            }
+            retry_count += 1;
+            file_state_before = file_state_now;
+            csv_headers = read_header(csv_path, config)?;
+            info!("CSV Headers after re-read {}: {:?}", retry_count, csv_headers);
+        }
+        let template_headers = normalize_file_header(&csv_headers, config);
+        let template_headers = template_headers.as_ref();
+        match find_template(template_headers, hashmap, config) {
+            Some(Template { headers: matched_headers, table_name }) => {
                 info!("Matching table headers found, table name: {:?}", table_name);
+                // Extra columns are expected with prefix matching, but a sign of an outdated template when only
+                // tolerated
+                let prefix_table = config.header_match_mode == HeaderMatchMode::Prefix
+                    || config.prefix_match_tables.contains(table_name);
+                if matched_headers != template_headers && !prefix_table {
+                    warn!(
+                        "File {:?} has extra trailing columns not in the template of table {}: [{}]. \
+                         Tolerated by TOLERATE_EXTRA_TRAILING_COLUMNS, the template should be updated.",
+                        csv_file_basename,
+                        table_name,
+                        &template_headers[matched_headers.len() + 1..]
+                    );
+                }
+                // Prefix matches have extra columns by definition, so only exact matches are counted
+                if config.strict_column_count && matched_headers == template_headers {
+                    // Trimming trailing delimiters can hide extra empty columns in the raw header
+                    let expected_columns = column_count(matched_headers);
+                    let actual_columns = column_count(&csv_headers);
+                    if expected_columns != actual_columns {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!(
+                                "Column count mismatch for table {}: expected {} columns, got {}",
+                                table_name, expected_columns, actual_columns
+                            ),
+                        ));
+                    }
+                }
                 return Ok(table_name.to_string());
             }
-            None => {
-                info!("No matching table headers found. Ignoring csv file.");
-                match PathBuf::from(csv_path).parent() {
-                    Some(log_dir) => log_upload_status(log_dir.to_str().unwrap(), format!("Upload failed! File: {csv_file_basename} Reason: No matching table headers found.").to_string()),
-                    None => error!("Failed to get parent directory of source file."),
+            None => {
+                info!("No matching table headers found. Ignoring csv file.");
+                warn_header_drift(template_headers, hashmap, config);
+                match PathBuf::from(csv_path).parent() {
+                    Some(log_dir) => log_upload_status(
+                        log_dir.to_str().unwrap(),
+                        csv_file_basename,
+                        "",
+                        Some(NO_MATCHING_HEADERS_REASON),
+                        config,
+                    ),
+                    None => error!("Failed to get parent directory of source file."),
+                }
+            }
+        }
+    }
+    // If csv file does not exist or no matching table headers, return empty string
+    Ok(String::new())
+}
+
+fn read_header_line(csv_path: &str, config: &Config) -> std::io::Result<String> {
+    // Read the header line, skipping leading lines before the header (e.g. title rows)
+    let reader = BufReader::new(File::open(csv_path)?);
+    reader
+        .lines()
+        .nth(config.header_row_index)
+        .unwrap_or_else(|| Ok(String::new()))
+}
+
+fn file_state(file_path: &str) -> Option<(u64, std::time::SystemTime)> {
+    // Size and modification time, used to detect whether a file is still being written
+    fs::metadata(file_path)
+        .and_then(|attr| Ok((attr.len(), attr.modified()?)))
+        .ok()
+}
+
+fn normalize_header(headers: &str) -> &str {
+    // Strip surrounding whitespace (including line endings) and any trailing delimiters, in any order
+    headers
+        .trim()
+        .trim_end_matches(|c: char| c == ',' || c.is_whitespace())
+}
+
+fn normalize_file_header<'a>(headers: &'a str, config: &Config) -> Cow<'a, str> {
+    // Normalize a CSV file header for matching. With STRICT_HEADER, trailing delimiters are kept
+    // so a header with extra trailing commas no longer matches its template.
+    // With IGNORE_HEADER_QUOTES, quoted columns are compared by their unquoted value.
+    fn trim_header<'a>(headers: &'a str, config: &Config) -> &'a str {
+        if config.strict_header {
+            headers.trim()
+        } else {
+            normalize_header(headers)
+        }
+    }
+    if config.ignore_header_quotes {
+        Cow::Owned(trim_header(&unquote_header(headers), config).to_string())
+    } else {
+        Cow::Borrowed(trim_header(headers, config))
+    }
+}
+
+fn unquote_header(headers: &str) -> String {
+    // Join the unquoted values of a header line's columns with commas
+    split_csv_fields(headers).join(",")
+}
+
+fn split_csv_fields(line: &str) -> Vec<String> {
+    // Split a line into its fields, honoring quoted fields with embedded delimiters. Quoted fields are unquoted,
+    // with "" unescaped and whitespace around the quotes dropped.
+    let mut fields: Vec<&str> = Vec::new();
+    let mut in_quotes = false;
+    let mut field_start = 0;
+    for (index, c) in line.char_indices() {
+        match c {
+            // An escaped quote toggles twice
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                fields.push(&line[field_start..index]);
+                field_start = index + 1;
+            }
+            _ => (),
+        }
+    }
+    fields.push(&line[field_start..]);
+    fields
+        .iter()
+        .map(|field| match field.trim().strip_prefix('"').and_then(|field| field.strip_suffix('"')) {
+            Some(value) => value.replace("\"\"", "\""),
+            None => field.to_string(),
+        })
+        .collect()
+}
+
+fn find_template<'a>(
+    headers: &str,
+    hashmap: &'a TemplateMap,
+    config: &Config,
+) -> Option<&'a Template> {
+    // Find template matching headers.
+    // An exact match always takes precedence and is looked up by header hash. Otherwise, among
+    // prefix enabled templates whose columns are a leading subset of the headers, the most
+    // specific (most columns) wins.
+    let exact_match = hashmap
+        .get(&header_hash(headers))
+        .and_then(|bucket| bucket.iter().find(|template| template.headers == headers));
+    if exact_match.is_some() || config.strict_header {
+        return exact_match;
+    }
+    hashmap
+        .values()
+        .flatten()
+        .filter(|template| {
+            config.header_match_mode == HeaderMatchMode::Prefix
+                || config.prefix_match_tables.contains(&template.table_name)
+                || config.tolerate_extra_trailing_columns
+        })
+        .filter(|template| headers.starts_with(&format!("{},", template.headers)))
+        .max_by_key(|template| column_count(&template.headers))
+}
+
+fn header_hash(headers: &str) -> u64 {
+    // Hash of the sorted column set of a normalized header
+    let mut columns: Vec<&str> = headers.split(',').collect();
+    columns.sort_unstable();
+    let mut hasher = DefaultHasher::new();
+    columns.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn column_differences<'a>(template_headers: &'a str, headers: &'a str) -> (Vec<&'a str>, Vec<&'a str>) {
+    // Columns of the template missing from headers, and columns of headers not in the template
+    let template_columns: Vec<&str> = template_headers.split(',').collect();
+    let columns: Vec<&str> = headers.split(',').collect();
+    let missing = template_columns.iter().filter(|column| !columns.contains(column)).copied().collect();
+    let extra = columns.iter().filter(|column| !template_columns.contains(column)).copied().collect();
+    (missing, extra)
+}
+
+fn warn_header_drift(headers: &str, hashmap: &TemplateMap, config: &Config) {
+    // Warn about the closest template of an unmatched header if its column set overlap
+    // (shared columns / all distinct columns) reaches HEADER_DRIFT_THRESHOLD, naming the differing columns
+    if config.header_drift_threshold <= 0.0 {
+        return;
+    }
+    let closest = hashmap
+        .values()
+        .flatten()
+        .map(|template| {
+            let (missing, extra) = column_differences(&template.headers, headers);
+            let shared = column_count(&template.headers) - missing.len();
+            let similarity = shared as f64 / (shared + missing.len() + extra.len()) as f64;
+            (similarity, template, missing, extra)
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0));
+    if let Some((similarity, template, missing, extra)) = closest {
+        if similarity >= config.header_drift_threshold {
+            warn!(
+                "Possible header drift: closest table {} ({:.0}% column overlap). Missing columns: [{}], unexpected columns: [{}]{}",
+                template.table_name,
+                similarity * 100.0,
+                missing.join(","),
+                extra.join(","),
+                if missing.is_empty() && extra.is_empty() { ", columns are reordered" } else { "" }
+            );
+        }
+    }
+}
+
+fn column_count(headers: &str) -> usize {
+    // Count header fields
+    headers.split(',').count()
+}
+
+fn delete_src_file_and_metadata(src_file: &str, src_file_metadata: &str, config: &Config) {
+    // Delete source file and metadata after rsync. With KEEP_LOCAL_METADATA the metadata file is kept.
+    if config.keep_local_metadata {
+        info!("Attempting to delete source file: {}", src_file);
+        remove_file_if_exists(src_file);
+        keep_metadata_file(src_file_metadata, config);
+        return;
+    }
+    info!(
+        "Attempting to delete source file and metadata: {}, {}",
+        src_file, src_file_metadata
+    );
+    for file in [src_file, src_file_metadata] {
+        remove_file_if_exists(file);
+    }
+}
+
+fn keep_metadata_file(src_file_metadata: &str, config: &Config) {
+    // Keep the metadata file of a deleted source file as a transfer ledger, in place or moved to
+    // METADATA_LEDGER_DIR
+    let Some(ledger_dir) = &config.metadata_ledger_dir else {
+        return;
+    };
+    if src_file_metadata.is_empty() {
+        return;
+    }
+    match archive_file(src_file_metadata, Path::new(ledger_dir), ArchiveMode::Move) {
+        Ok(ledger_path) => info!("Moved {} to {}", src_file_metadata, ledger_path.display()),
+        Err(e) => error!("Failed to move {} to {}. Keeping it. Error: {}", src_file_metadata, ledger_dir, e),
+    }
+}
+
+fn queue_delayed_deletion(src_file: &str, src_file_metadata: &str, config: &Config) {
+    // Append a synced file and its companions to the delete queue, due DELETE_DELAY_SECONDS from now.
+    // Each line is "<due unix timestamp>\t<file>\t<file>..."
+    let due = chrono::Utc::now().timestamp() + config.delete_delay_seconds as i64;
+    let mut files = vec![src_file.to_string()];
+    if config.keep_local_metadata {
+        keep_metadata_file(src_file_metadata, config);
+    } else {
+        files.push(src_file_metadata.to_string());
+    }
+    if config.source_tag == SourceTag::Marker {
+        files.push(marker_file_path(src_file));
+    }
+    let _guard = DELETE_QUEUE_LOCK.lock().unwrap();
+    let result = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&config.delete_queue_file)
+        .and_then(|mut queue| writeln!(queue, "{}\t{}", due, files.join("\t")));
+    match result {
+        Ok(_) => info!(
+            "Queued {} for deletion in {} seconds",
+            src_file, config.delete_delay_seconds
+        ),
+        Err(e) => error!(
+            "Failed to queue {} for delayed deletion, keeping it. Error: {}",
+            src_file, e
+        ),
+    }
+}
+
+fn process_delete_queue(delete_queue_file: &str) -> std::io::Result<()> {
+    // Delete queued files whose delay has elapsed and rewrite the queue with the remaining entries.
+    // The queue is replaced atomically so an interrupted rewrite never loses entries.
+    let _guard = DELETE_QUEUE_LOCK.lock().unwrap();
+    let queue = match fs::read_to_string(delete_queue_file) {
+        Ok(queue) => queue,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let now = chrono::Utc::now().timestamp();
+    let mut remaining: Vec<&str> = Vec::new();
+    for line in queue.lines().filter(|line| !line.trim().is_empty()) {
+        let mut fields = line.split('\t');
+        match fields.next().and_then(|due| due.parse::<i64>().ok()) {
+            Some(due) if due <= now => fields.for_each(remove_file_if_exists),
+            Some(_) => remaining.push(line),
+            None => warn!("Dropping invalid delete queue entry {:?}", line),
+        }
+    }
+    if remaining.is_empty() {
+        return fs::remove_file(delete_queue_file);
+    }
+    if remaining.len() != queue.lines().count() {
+        let tmp_file = format!("{}.tmp", delete_queue_file);
+        fs::write(&tmp_file, format!("{}\n", remaining.join("\n")))?;
+        fs::rename(&tmp_file, delete_queue_file)?;
+    }
+    Ok(())
+}
+
+fn run_delayed_deletions(delete_queue_file: String) {
+    // Background loop deleting queued files once due. Entries left at shutdown are resumed on restart.
+    loop {
+        if let Err(e) = process_delete_queue(&delete_queue_file) {
+            error!("Failed to process delete queue {}: {}", delete_queue_file, e);
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn remove_file_if_exists(file: &str) {
+    // Remove file, treating an empty path (metadata creation failed) or a missing file as already removed
+    if file.is_empty() {
+        debug!("Skipping removal of empty path");
+        return;
+    }
+    match fs::remove_file(file) {
+        Ok(_) => info!("Successfully removed {}", file),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => debug!("{} already removed", file),
+        Err(e) => error!("Failed to remove {}: {}", file, e),
+    }
+}
+
+fn tag_source_file(src_file: &str, config: &Config) {
+    // Record on the source file that the daemon handled it, for provenance of kept or archived files.
+    // Tagging failures (e.g. filesystem without xattr support) are logged and do not stop the transfer.
+    let tag_value = format_timestamp(
+        chrono::Utc::now(),
+        &config.metadata_time_format,
+        config.metadata_timezone,
+    );
+    match config.source_tag {
+        SourceTag::None => (),
+        SourceTag::Xattr => match xattr::set(src_file, "user.rsync_csv.uploaded", tag_value.as_bytes()) {
+            Ok(()) => debug!("Tagged {} with xattr", src_file),
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                warn!("Failed to set xattr on {}: filesystem without xattr support", src_file)
+            }
+            Err(e) => warn!("Failed to set xattr on {}: {}", src_file, e),
+        },
+        SourceTag::Marker => {
+            let marker_file = marker_file_path(src_file);
+            if let Err(e) = fs::write(&marker_file, format!("user.rsync_csv.uploaded={}\n", tag_value)) {
+                warn!("Failed to write marker file {}: {}", marker_file, e);
+            }
+        }
+    }
+}
+
+fn marker_file_path(src_file: &str) -> String {
+    format!("{}.uploaded", src_file)
+}
+
+fn archive_synced_file(src_file: &str, src_file_metadata: &str, archive_dir: &str, config: &Config) {
+    // Move synced source file (and optionally metadata) into a date structured archive tree.
+    // If archiving fails, the source file is kept in place rather than failing the sync.
+    let archive_date_dir = PathBuf::from(archive_dir).join(format_timestamp(
+        chrono::Utc::now(),
+        "%Y/%m/%d",
+        config.metadata_timezone,
+    ));
+    match archive_file(src_file, &archive_date_dir, config.archive_mode) {
+        Ok(archive_path) => info!("Archived {} to {}", src_file, archive_path.display()),
+        Err(e) => {
+            error!("Failed to archive {}. Keeping source file. Error: {}", src_file, e);
+            return;
+        }
+    }
+    if config.source_tag == SourceTag::Marker {
+        let marker_file = marker_file_path(src_file);
+        if let Err(e) = archive_file(&marker_file, &archive_date_dir, config.archive_mode) {
+            error!("Failed to archive {}. Error: {}", marker_file, e);
+        }
+    }
+    if config.archive_metadata && !src_file_metadata.is_empty() {
+        match archive_file(src_file_metadata, &archive_date_dir, config.archive_mode) {
+            Ok(archive_path) => info!("Archived {} to {}", src_file_metadata, archive_path.display()),
+            Err(e) => error!(
+                "Failed to archive {}. Keeping metadata file. Error: {}",
+                src_file_metadata, e
+            ),
+        }
+    } else {
+        remove_file_if_exists(src_file_metadata);
+    }
+}
+
+struct DeadLetter<'a> {
+    table_name: &'a str,
+    reason: &'a str,
+    attempts: u32,
+}
+
+fn dead_letter_file(
+    src_file: &str,
+    src_file_metadata: &str,
+    sidecar_file: Option<&String>,
+    dead_letter_dir: &str,
+    failure: &DeadLetter,
+    config: &Config,
+) {
+    // Move a file that failed all transfer attempts, with its companions, into the dead letter directory
+    // and describe the failure in a <file>.error file next to it. If the move fails, the file is kept in place.
+    let dead_letter_dir = Path::new(dead_letter_dir);
+    match archive_file(src_file, dead_letter_dir, ArchiveMode::Move) {
+        Ok(dead_letter_path) => warn!("Moved {} to dead letter directory {}", src_file, dead_letter_path.display()),
+        Err(e) => {
+            error!("Failed to move {} to dead letter directory. Keeping it. Error: {}", src_file, e);
+            return;
+        }
+    }
+    let mut companions = vec![src_file_metadata.to_string()];
+    companions.extend(sidecar_file.cloned());
+    if config.source_tag == SourceTag::Marker {
+        companions.push(marker_file_path(src_file));
+    }
+    for companion in companions.iter().filter(|file| Path::new(file).exists()) {
+        if let Err(e) = archive_file(companion, dead_letter_dir, ArchiveMode::Move) {
+            error!("Failed to move {} to dead letter directory. Error: {}", companion, e);
+        }
+    }
+    let error_file = dead_letter_dir.join(format!(
+        "{}.error",
+        Path::new(src_file).file_name().unwrap().to_string_lossy()
+    ));
+    let error_data = format!(
+        "time: {}\ntable: {}\nattempts: {}\nreason: {}\n",
+        chrono::Local::now().to_rfc3339(),
+        failure.table_name,
+        failure.attempts,
+        failure.reason.trim()
+    );
+    if let Err(e) = fs::write(&error_file, error_data) {
+        error!("Failed to write {}: {}", error_file.display(), e);
+    }
+}
+
+fn archive_file(
+    file_path: &str,
+    archive_date_dir: &Path,
+    archive_mode: ArchiveMode,
+) -> std::io::Result<PathBuf> {
+    // Move file into the archive directory, creating it if needed
+    fs::create_dir_all(archive_date_dir)?;
+    let archive_path = archive_date_dir.join(Path::new(file_path).file_name().unwrap());
+    move_file(Path::new(file_path), &archive_path, archive_mode)?;
+    Ok(archive_path)
+}
+
+fn move_file(from: &Path, to: &Path, mode: ArchiveMode) -> std::io::Result<()> {
+    // Rename file, falling back to copy and remove if source and target are on different filesystems.
+    // Unlike rename, copy and remove is not atomic: a crash in between leaves the file in both places.
+    if mode == ArchiveMode::Move {
+        match fs::rename(from, to) {
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => info!(
+                "Cannot rename {} across filesystems. Falling back to copy and remove.",
+                from.display()
+            ),
+            result => return result,
+        }
+    }
+    if let Err(e) = fs::copy(from, to) {
+        // Do not leave a partial copy behind
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    fs::remove_file(from)
+}
+
+fn log_upload_status(
+    log_dir: &str,
+    file: &str,
+    table_name: &str,
+    failure_reason: Option<&str>,
+    config: &Config,
+) {
+    // Create an upload log file at specified log directory
+    let log_file_path = Path::new(log_dir).join("upload.log");
+    if let Some(file_results) = FILE_RESULTS.lock().unwrap().as_mut() {
+        let file_path = Path::new(log_dir).join(file);
+        let duration = file_results.start_times.get(&file_path).map(Instant::elapsed).unwrap_or_default();
+        file_results.results.push(FileResult {
+            bytes: fs::metadata(&file_path).map(|attr| attr.len()).ok(),
+            file: file_path.display().to_string(),
+            table_name: Some(table_name.to_string()).filter(|table_name| !table_name.is_empty()),
+            status: match failure_reason {
+                None => "succeeded",
+                Some(NO_MATCHING_HEADERS_REASON) => "unmatched",
+                Some(_) => "failed",
+            },
+            reason: failure_reason.map(|reason| reason.trim().to_string()),
+            duration,
+        });
+    }
+    let log_line = match config.upload_log_format {
+        UploadLogFormat::Text => {
+            let log_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+            let host = match &config.source_hostname {
+                Some(hostname) => format!(" Host: {hostname}"),
+                None => String::new(),
+            };
+            match failure_reason {
+                Some(reason) => format!("{log_time} - Upload failed! File: {file}{host} Reason: {reason}"),
+                None => format!("{log_time} - Upload succeeded! File: {file}{host}"),
+            }
+        }
+        UploadLogFormat::Json => {
+            let mut log_json = serde_json::json!({
+                "status": if failure_reason.is_some() { "failed" } else { "succeeded" },
+                "file": file,
+                "table": Some(table_name).filter(|table_name| !table_name.is_empty()),
+                "reason": failure_reason.map(str::trim),
+                "timestamp": chrono::Local::now().to_rfc3339(),
+            });
+            if let Some(hostname) = &config.source_hostname {
+                log_json["host"] = serde_json::json!(hostname);
+            }
+            log_json.to_string()
+        }
+    };
+    let _guard = UPLOAD_LOG_LOCK.lock().unwrap();
+    match fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(log_file_path)
+    {
+        Ok(mut log_file) => match log_file.write(format!("{log_line}\n").as_bytes()) {
+            Ok(_) => info!("Upload log file updated successfully."),
+            Err(e) => error!("Failed to write to upload log file. Error: {}", e),
+        },
+        Err(e) => error!("Failed to create upload log file. Error: {}", e),
+    }
+}
+
+fn run_rsync(
+    rsync_hashmap: &HashMap<String, HashMap<String, Vec<String>>>,
+    config: &Config,
+    summary: &mut BatchSummary,
+) -> Result<Vec<String>, String> {
+    // Run rsync command to sync csv files to destination host.
+    // Returns the source files to handle again in the next cycle (see REQUEUE_CHANGED_FILES).
+    debug!("Rsync Hashmap: {:?}", rsync_hashmap);
+    // Each job transfers its groups one after the other. Ordered tables have a single job of one group per file.
+    let mut transfer_jobs: VecDeque<(&String, Vec<(&String, SyncGroup)>)> = VecDeque::new();
+    // Tables and their files are processed in sorted order so batches are reproducible across runs
+    let mut table_names: Vec<&String> = rsync_hashmap.keys().collect();
+    table_names.sort();
+    for table_name in table_names {
+        let table_entry = rsync_hashmap.get(table_name).unwrap();
+        let src_files = table_entry.get("src_files").unwrap();
+        let metadata_files = table_entry.get("metadata_files").unwrap();
+        let dest_dirs = table_entry.get("dest_dirs").unwrap();
+        let md5_files = table_entry.get("md5_files");
+        let upload_files = table_entry.get("upload_files").unwrap_or(src_files);
+        let ordered = config.ordered_tables.contains(table_name);
+        // Files of the same table can map to different remote directories, one rsync per directory.
+        // Files of ordered tables are sent one at a time, oldest modification time first.
+        let mut dest_dir_groups: Vec<(&String, SyncGroup)> = Vec::new();
+        let mut file_indexes: Vec<usize> = (0..src_files.len()).collect();
+        if ordered {
+            file_indexes.sort_by_cached_key(|&index| {
+                let modified = fs::metadata(&src_files[index]).and_then(|attr| attr.modified());
+                (modified.unwrap_or(std::time::UNIX_EPOCH), &src_files[index])
+            });
+        } else {
+            file_indexes.sort_by_key(|&index| (&dest_dirs[index], &src_files[index]));
+        }
+        for index in file_indexes {
+            let dest_dir = &dest_dirs[index];
+            if ordered || dest_dir_groups.last().is_none_or(|(group_dest_dir, _)| *group_dest_dir != dest_dir) {
+                dest_dir_groups.push((dest_dir, SyncGroup::default()));
+            }
+            let (_, group) = dest_dir_groups.last_mut().unwrap();
+            group.src_files.push(src_files[index].clone());
+            group.upload_files.push(upload_files[index].clone());
+            group.metadata_files.push(metadata_files[index].clone());
+            if let Some(dest_file_name) = &config.dest_file_name {
+                group
+                    .dest_names
+                    .push(render_dest_file_name(dest_file_name, &src_files[index], table_name, config));
+            }
+            if let Some(md5_files) = md5_files {
+                group.sidecar_files.push(md5_files[index].clone());
+            }
+        }
+        let schema_file = config
+            .table_schema_files
+            .iter()
+            .find(|(table, _)| table == table_name)
+            .map(|(_, schema_file)| schema_file);
+        for (dest_dir, group) in dest_dir_groups.iter_mut() {
+            if let Some(schema_file) = schema_file {
+                let schema_sent = config.schema_transfer == SchemaTransfer::Once
+                    && SCHEMA_SENT_DIRS.lock().unwrap().get_or_insert_default().contains(*dest_dir);
+                if !schema_sent {
+                    group.schema_files.push(schema_file.clone());
+                }
+            }
+            if config.batch_manifest {
+                match create_batch_manifest(table_name, group, config) {
+                    Ok(manifest_file) => group.batch_files.push(manifest_file),
+                    Err(e) => error!("Error creating batch manifest for table {}: {:?}", table_name, e),
+                }
+            }
+        }
+        if ordered {
+            transfer_jobs.push_back((table_name, dest_dir_groups));
+        } else {
+            transfer_jobs.extend(dest_dir_groups.into_iter().map(|dest_dir_group| (table_name, vec![dest_dir_group])));
+        }
+    }
+    if config.check_dest_free_space && !transfer_jobs.is_empty() {
+        let batch_bytes: u64 = transfer_jobs
+            .iter()
+            .flat_map(|(_, groups)| groups)
+            .flat_map(|(_, group)| transfer_files(group))
+            .filter_map(|file| fs::metadata(file).ok())
+            .map(|attr| attr.len())
+            .sum();
+        if let Err(reason) = check_dest_free_space(batch_bytes, config) {
+            error!("Aborting batch, keeping its files. {}", reason);
+            let summary_state = Mutex::new(std::mem::take(summary));
+            for (table_name, groups) in &transfer_jobs {
+                for (_, group) in groups {
+                    skip_group(table_name, group, &reason, config, &summary_state);
+                }
+            }
+            *summary = summary_state.into_inner().unwrap();
+            return Err(reason);
+        }
+    }
+    if config.check_dest_writable {
+        // All files of a table are kept if one of its directories is not writable, which keeps ordered tables in order
+        let summary_state = Mutex::new(std::mem::take(summary));
+        transfer_jobs.retain(|(table_name, groups)| {
+            match groups.iter().find_map(|(dest_dir, _)| check_dest_writable(dest_dir, config).err()) {
+                Some(reason) => {
+                    error!("Skipping table {}, keeping its files. {}", table_name, reason);
+                    for (_, group) in groups {
+                        skip_group(table_name, group, &reason, config, &summary_state);
+                    }
+                    false
+                }
+                None => true,
+            }
+        });
+        *summary = summary_state.into_inner().unwrap();
+    }
+    if !transfer_jobs.is_empty() {
+        sleep_jitter("batch", config.transfer_jitter_max_ms);
+    }
+    // Up to RSYNC_PARALLELISM workers pull transfers from a shared queue
+    let worker_count = config.rsync_parallelism.clamp(1, transfer_jobs.len().max(1));
+    let transfer_jobs = Mutex::new(transfer_jobs);
+    let transfer_state = TransferState {
+        summary: Mutex::new(std::mem::take(summary)),
+        active_transfers: AtomicUsize::new(0),
+        changed_files: Mutex::new(HashSet::new()),
+        requeued_files: Mutex::new(Vec::new()),
+    };
+    let inter_transfer_delay = Duration::from_secs(config.inter_transfer_delay_seconds);
+    let batch_id = current_batch_id();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..worker_count)
+            .map(|worker_index| {
+                // In parallel, INTER_TRANSFER_DELAY_SECONDS staggers the start of the workers
+                if worker_index > 0 && !inter_transfer_delay.is_zero() {
+                    thread::sleep(inter_transfer_delay);
+                }
+                scope.spawn(|| {
+                    // Log lines of the transfers keep the ID of the batch
+                    let _batch_id_scope = BatchIdScope::enter(batch_id.clone());
+                    loop {
+                        let transfer_job = transfer_jobs.lock().unwrap().pop_front();
+                        match transfer_job {
+                            Some((table_name, groups)) => {
+                                let mut groups = groups.iter();
+                                let mut skip_reason = None;
+                                for (dest_dir, group) in groups.by_ref() {
+                                    // Later files of an ordered table wait until an earlier one is synced
+                                    match rsync_files(table_name, group, dest_dir, config, &transfer_state) {
+                                        Ok(0) => {}
+                                        Ok(_) => {
+                                            skip_reason = Some(format!(
+                                                "Not transferred: an earlier file of ordered table {} was not synced",
+                                                table_name
+                                            ));
+                                            break;
+                                        }
+                                        Err(e) => {
+                                            // The worker keeps draining the queue, the files of the table are kept
+                                            error!("Transfer of table {} to {} failed: {}", table_name, dest_dir, e);
+                                            skip_group(table_name, group, &e, config, &transfer_state.summary);
+                                            skip_reason = Some(e);
+                                            break;
+                                        }
+                                    }
+                                }
+                                if let Some(reason) = skip_reason {
+                                    for (_, group) in groups {
+                                        skip_group(table_name, group, &reason, config, &transfer_state.summary);
+                                    }
+                                }
+                                // In serial, INTER_TRANSFER_DELAY_SECONDS pauses between consecutive transfers
+                                if worker_count == 1
+                                    && !inter_transfer_delay.is_zero()
+                                    && !transfer_jobs.lock().unwrap().is_empty()
+                                {
+                                    info!(
+                                        "Waiting {} seconds before the next transfer",
+                                        config.inter_transfer_delay_seconds
+                                    );
+                                    thread::sleep(inter_transfer_delay);
+                                }
+                            }
+                            None => return,
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers.into_iter().for_each(|worker| worker.join().unwrap());
+    });
+    *summary = transfer_state.summary.into_inner().unwrap();
+    Ok(transfer_state.requeued_files.into_inner().unwrap())
+}
+
+fn sleep_jitter(reason: &str, jitter_max_ms: u64) {
+    // Sleep a random duration up to jitter_max_ms so daemons sharing a destination don't transfer at once
+    if jitter_max_ms == 0 {
+        return;
+    }
+    // RandomState keys are seeded randomly once per thread, then incremented for each new instance, so the hash
+    // differs between daemons and between calls. It's not a uniform random source, but enough to spread load.
+    let jitter_ms = RandomState::new().build_hasher().finish() % (jitter_max_ms + 1);
+    info!("Applying {} jitter of {} ms before transfers", reason, jitter_ms);
+    thread::sleep(Duration::from_millis(jitter_ms));
+}
+
+#[derive(Default)]
+struct SyncGroup {
+    src_files: Vec<String>,
+    // Index aligned with src_files, the file sent in place of each source file (a temporary copy with renamed columns)
+    upload_files: Vec<String>,
+    metadata_files: Vec<String>,
+    // Per file companions (e.g. md5 sidecars), removed together with the source file after sync
+    sidecar_files: Vec<String>,
+    // Per batch companions (e.g. manifests) generated outside the source directory, removed after the transfer
+    batch_files: Vec<String>,
+    // Table schema files sent along with the batch, which are kept after the transfer
+    schema_files: Vec<String>,
+    // Index aligned with upload_files, the remote file names from DEST_FILE_NAME (empty if not set)
+    dest_names: Vec<String>,
+}
+
+struct TransferState {
+    summary: Mutex<BatchSummary>,
+    active_transfers: AtomicUsize,
+    // Names of files rsync reported as vanished or changed while they were read, on any destination
+    changed_files: Mutex<HashSet<String>>,
+    requeued_files: Mutex<Vec<String>>,
+}
+
+fn rsync_files(
+    table_name: &str,
+    group: &SyncGroup,
+    dest_dir: &str,
+    config: &Config,
+    transfer_state: &TransferState,
+) -> Result<usize, String> {
+    let src_files = &group.src_files;
+    let metadata_files = &group.metadata_files;
+    // Rsync source files and their metadata to a single remote directory on every destination host.
+    // Returns the number of files that were not synced (failed or re-queued).
+    let dest_hosts: Vec<&String> = std::iter::once(&config.dest_host)
+        .chain(&config.fanout_hosts)
+        .collect();
+    // With RSYNC_FILES_FROM, the files are listed once in a file shared by all destination hosts
+    let file_list = if config.rsync_files_from && config.transfer_tool == TransferTool::Rsync {
+        match create_file_list(&transfer_files(group).collect::<Vec<&String>>()) {
+            Ok(file_list) => Some(file_list),
+            Err(e) => {
+                error!(
+                    "Error creating rsync file list for table {}, passing files as arguments: {:?}",
+                    table_name, e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    transfer_state.active_transfers.fetch_add(1, Ordering::SeqCst);
+    let result = (|| {
+        let mut not_synced = 0;
+        let mut host_results: Vec<(&String, HostTransfer)> = Vec::new();
+        // Most rsync attempts made for any destination, recorded for dead lettered files
+        let mut attempts = 0;
+        for dest_host in &dest_hosts {
+            let (host_result, host_attempts) =
+                rsync_to_host(group, file_list.as_ref(), dest_host, dest_dir, config, transfer_state)?;
+            attempts = attempts.max(host_attempts);
+            if let Some((_, owner)) = config.table_dest_owners.iter().find(|(table, _)| table == table_name) {
+                chown_dest_files(group, &host_result.transferred, dest_host, dest_dir, owner, config);
+            }
+            host_results.push((dest_host, host_result));
+        }
+        let failed_host_names: Vec<&str> = host_results
+            .iter()
+            .filter(|(_, host_result)| host_result.error.is_some())
+            .map(|(dest_host, _)| dest_host.as_str())
+            .collect();
+        if config.schema_transfer == SchemaTransfer::Once {
+            // Schema files reaching every destination are not sent again to this directory
+            let schema_sent = group.schema_files.iter().all(|schema_file| {
+                let schema_basename = Path::new(schema_file).file_name().unwrap().to_str().unwrap();
+                host_results.iter().all(|(_, host_result)| host_result.transferred.contains(schema_basename))
+            });
+            if !group.schema_files.is_empty() && schema_sent {
+                SCHEMA_SENT_DIRS.lock().unwrap().get_or_insert_default().insert(dest_dir.to_string());
+            }
+        }
+        if config.rollup_manifest {
+            for batch_file in &group.batch_files {
+                let batch_file_basename = Path::new(batch_file).file_name().unwrap().to_str().unwrap();
+                let delivered_count = host_results
+                    .iter()
+                    .filter(|(_, host_result)| host_result.transferred.contains(batch_file_basename))
+                    .count();
+                let delivered = match config.fanout_policy {
+                    FanoutPolicy::All => delivered_count == dest_hosts.len(),
+                    FanoutPolicy::Any => delivered_count > 0,
+                };
+                if delivered {
+                    record_rollup_entry(table_name, dest_dir, batch_file, config);
+                }
+            }
+        }
+        if !failed_host_names.is_empty() && dest_hosts.len() > 1 {
+            warn!(
+                "Transfer of table {} to {} failed on destinations: {}",
+                table_name,
+                dest_dir,
+                failed_host_names.join(",")
+            );
+        }
+        for (index, (src_file, src_file_metadata)) in src_files.iter().zip(metadata_files).enumerate() {
+            let binding = PathBuf::from(src_file);
+            let src_file_basename = binding.file_name().unwrap().to_str().unwrap();
+            let upload_file_basename = Path::new(&group.upload_files[index]).file_name().unwrap().to_str().unwrap();
+            // Reasons the file did not reach each destination, if any
+            let host_failures: Vec<(&String, String)> = host_results
+                .iter()
+                .filter_map(|(dest_host, host_result)| match &host_result.error {
+                    _ if host_result.transferred.contains(upload_file_basename) => None,
+                    None => Some((*dest_host, "File was not transferred by rsync".to_string())),
+                    Some(err_msg) => Some((*dest_host, err_msg.clone())),
+                })
+                .collect();
+            let synced = match config.fanout_policy {
+                FanoutPolicy::All => host_failures.is_empty(),
+                FanoutPolicy::Any => host_failures.len() < dest_hosts.len(),
+            };
+            if !synced
+                && config.requeue_changed_files
+                && transfer_state.changed_files.lock().unwrap().contains(upload_file_basename)
+            {
+                // The metadata, sidecar and marker files are created again for the updated file
+                let marker_file = marker_file_path(src_file);
+                let companions = [src_file_metadata.as_str(), &marker_file]
+                    .into_iter()
+                    .chain(group.sidecar_files.get(index).map(String::as_str));
+                companions.for_each(remove_file_if_exists);
+                if Path::new(src_file).exists() {
+                    warn!("{} changed during transfer. Re-queuing it for the next cycle.", src_file);
+                    transfer_state.requeued_files.lock().unwrap().push(src_file.clone());
+                    transfer_state.summary.lock().unwrap().requeued += 1;
+                } else {
+                    warn!("{} vanished during transfer and is not re-queued", src_file);
+                }
+                not_synced += 1;
+                continue;
+            }
+            if !synced {
+                // With a single destination the rsync error is the reason, otherwise name each failed host
+                let err_msg = match host_failures.as_slice() {
+                    [(_, err_msg)] if dest_hosts.len() == 1 => err_msg.clone(),
+                    _ => host_failures
+                        .iter()
+                        .map(|(dest_host, err_msg)| format!("Failed destination {}: {}", dest_host, err_msg.trim()))
+                        .collect::<Vec<String>>()
+                        .join("; "),
+                };
+                transfer_state.summary.lock().unwrap().record_failure(table_name, &err_msg);
+                match PathBuf::from(src_file).parent() {
+                    Some(log_dir) => log_upload_status(
+                        log_dir.to_str().unwrap(),
+                        src_file_basename,
+                        table_name,
+                        Some(&err_msg),
+                        config,
+                    ),
+                    None => {
+                        error!("Failed to get source file parent directory");
+                        Err("Failed to get source file parent directory")?;
+                    }
+                }
+                // The files themselves are fine when the destination is full, so they stay in the source directory
+                let dest_full = host_failures.iter().any(|(_, err_msg)| err_msg.starts_with(DEST_FULL_REASON));
+                if let Some(dead_letter_dir) = config.dead_letter_dir.as_ref().filter(|_| !dest_full) {
+                    let failure = DeadLetter {
+                        table_name,
+                        reason: &err_msg,
+                        attempts,
+                    };
+                    dead_letter_file(
+                        src_file,
+                        src_file_metadata,
+                        group.sidecar_files.get(index),
+                        dead_letter_dir,
+                        &failure,
+                        config,
+                    );
+                }
+                not_synced += 1;
+                continue;
+            }
+            if let Some(sidecar_file) = group.sidecar_files.get(index) {
+                remove_file_if_exists(sidecar_file);
+            }
+            // Sized and logged before the source file is archived or deleted
+            let upload_file_bytes = fs::metadata(&group.upload_files[index]).map(|attr| attr.len()).unwrap_or(0);
+            match PathBuf::from(src_file).parent() {
+                Some(log_dir) => log_upload_status(
+                    log_dir.to_str().unwrap(),
+                    src_file_basename,
+                    table_name,
+                    None,
+                    config,
+                ),
+                None => {
+                    error!("Failed to get source file parent directory");
+                    Err("Failed to get source file parent directory")?;
+                }
+            }
+            match &config.archive_dir {
+                Some(archive_dir) => archive_synced_file(
+                    src_file,
+                    src_file_metadata,
+                    archive_dir,
+                    config,
+                ),
+                None if config.delete_delay_seconds > 0 => {
+                    queue_delayed_deletion(src_file, src_file_metadata, config)
+                }
+                None => {
+                    delete_src_file_and_metadata(src_file, src_file_metadata, config);
+                    if config.source_tag == SourceTag::Marker {
+                        remove_file_if_exists(&marker_file_path(src_file));
+                    }
+                }
+            }
+            {
+                let mut summary = transfer_state.summary.lock().unwrap();
+                summary.record_success(table_name);
+                summary.succeeded_bytes += upload_file_bytes;
+            }
+        }
+        Ok(not_synced)
+    })();
+    transfer_state.active_transfers.fetch_sub(1, Ordering::SeqCst);
+    remove_group_temp_files(group);
+    // The file list is written directly in the temporary directory, which must not be removed
+    if let Some((list_file, _)) = &file_list {
+        remove_file_if_exists(list_file);
+    }
+    result
+}
+
+fn skip_group(table_name: &str, group: &SyncGroup, reason: &str, config: &Config, summary: &Mutex<BatchSummary>) {
+    // Keep the files of a group that is not transferred in the source directory, reporting them as failed
+    for src_file in &group.src_files {
+        warn!("{}: {}", src_file, reason);
+        summary.lock().unwrap().record_failure(table_name, reason);
+        if let Some(log_dir) = Path::new(src_file).parent() {
+            log_upload_status(
+                log_dir.to_str().unwrap(),
+                Path::new(src_file).file_name().unwrap().to_str().unwrap(),
+                table_name,
+                Some(reason),
+                config,
+            );
+        }
+    }
+    remove_group_temp_files(group);
+}
+
+fn check_dest_free_space(batch_bytes: u64, config: &Config) -> Result<(), String> {
+    // Check that DEST_DIR has room for the batch plus DEST_FREE_SPACE_MARGIN_PERCENT on every destination host,
+    // reading the available space with POSIX df over ssh. Hosts whose space can't be read are not blocking.
+    let required_bytes = batch_bytes + batch_bytes * config.dest_free_space_margin_percent / 100;
+    for dest_host in std::iter::once(&config.dest_host).chain(&config.fanout_hosts) {
+        let mut ssh_command = Command::new("ssh");
+        for option in ssh_options(config) {
+            ssh_command.arg("-o").arg(option);
+        }
+        run_as(&mut ssh_command, config);
+        let ssh_connection = SshConnection::acquire(config);
+        let df_output = ssh_command
+            .arg(format!("{}@{}", config.dest_user, dest_host))
+            .arg(format!("df -Pk -- {}", shell_quote(&config.dest_dir)))
+            .output();
+        drop(ssh_connection);
+        // The second line is "filesystem 1024-blocks used available capacity mountpoint"
+        let available_bytes = match df_output {
+            Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .nth(1)
+                .and_then(|line| line.split_whitespace().nth(3))
+                .and_then(|available| available.parse::<u64>().ok())
+                .map(|available_kib| available_kib * 1024),
+            Ok(output) => {
+                warn!(
+                    "Failed to read free space on {}: {}",
+                    dest_host,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to execute ssh command. Error: {}", e);
+                continue;
+            }
+        };
+        match available_bytes {
+            Some(available_bytes) if available_bytes < required_bytes => {
+                return Err(format!(
+                    "Insufficient free space on {}:{}: {} bytes available, batch needs {} bytes including a {}% margin",
+                    dest_host, config.dest_dir, available_bytes, required_bytes, config.dest_free_space_margin_percent
+                ));
+            }
+            Some(available_bytes) => info!(
+                "Free space on {}:{}: {} bytes available, batch needs {} bytes",
+                dest_host, config.dest_dir, available_bytes, required_bytes
+            ),
+            None => warn!("Failed to parse df output for free space on {}", dest_host),
+        }
+    }
+    Ok(())
+}
+
+fn check_dest_writable(dest_dir: &str, config: &Config) -> Result<(), String> {
+    // Create and remove a test file in a remote directory over ssh, on every destination host.
+    // Successful checks are cached, failed ones are repeated for the next batch.
+    // Hosts where ssh itself fails are not blocking, the transfer reports the connection error.
+    let mut failures: Vec<String> = Vec::new();
+    let dest_hosts: Vec<&String> = std::iter::once(&config.dest_host).chain(&config.fanout_hosts).collect();
+    for dest_host in &dest_hosts {
+        let cache_key = format!("{}:{}", dest_host, dest_dir);
+        if WRITABLE_DEST_DIRS.lock().unwrap().get_or_insert_default().contains(&cache_key) {
+            continue;
+        }
+        // The directory is created as rsync would, so a missing table directory is not reported
+        let mkdir_command = match &config.remote_dir_mode {
+            Some(mode) => format!("mkdir -m {} -p -- {}", mode, shell_quote(dest_dir)),
+            None => format!("mkdir -p -- {}", shell_quote(dest_dir)),
+        };
+        let test_file = shell_quote(&format!("{}/.rsync_csv_write_test", dest_dir));
+        let mut ssh_command = Command::new("ssh");
+        for option in ssh_options(config) {
+            ssh_command.arg("-o").arg(option);
+        }
+        run_as(&mut ssh_command, config);
+        let ssh_connection = SshConnection::acquire(config);
+        let test_output = ssh_command
+            .arg(format!("{}@{}", config.dest_user, dest_host))
+            .arg(format!(
+                "{} && touch -- {}.$$ && rm -f -- {}.$$",
+                mkdir_command, test_file, test_file
+            ))
+            .output();
+        drop(ssh_connection);
+        match test_output {
+            Ok(output) if output.status.success() => {
+                info!("Destination {} is writable", cache_key);
+                WRITABLE_DEST_DIRS.lock().unwrap().get_or_insert_default().insert(cache_key);
+            }
+            // ssh exits with 255 on connection errors, anything else is the status of the remote command
+            Ok(output) if output.status.code() == Some(255) => warn!(
+                "Failed to check if {} is writable: {}",
+                cache_key,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Ok(output) => failures.push(format!(
+                "Destination not writable: {}: {}",
+                cache_key,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(e) => warn!("Failed to execute ssh command. Error: {}", e),
+        }
+    }
+    let blocking = match config.fanout_policy {
+        FanoutPolicy::All => !failures.is_empty(),
+        FanoutPolicy::Any => failures.len() == dest_hosts.len(),
+    };
+    if blocking {
+        Err(failures.join("; "))
+    } else {
+        Ok(())
+    }
+}
+
+fn remove_group_temp_files(group: &SyncGroup) {
+    // Batch files and renamed copies are regenerated for every batch, so they are removed whatever the outcome
+    let renamed_files = group
+        .upload_files
+        .iter()
+        .zip(&group.src_files)
+        .filter(|(upload_file, src_file)| upload_file != src_file)
+        .map(|(upload_file, _)| upload_file);
+    for batch_file in group.batch_files.iter().chain(renamed_files) {
+        remove_file_if_exists(batch_file);
+        if let Some(batch_file_dir) = Path::new(batch_file).parent() {
+            let _ = fs::remove_dir(batch_file_dir);
+        }
+    }
+}
+
+fn transfer_files(group: &SyncGroup) -> impl Iterator<Item = &String> {
+    // Files sent by rsync for a group. Metadata and sidecar paths are empty if their creation failed.
+    group
+        .upload_files
+        .iter()
+        .chain(&group.metadata_files)
+        .chain(&group.sidecar_files)
+        .chain(&group.batch_files)
+        .chain(&group.schema_files)
+        .filter(|file| !file.is_empty())
+}
+
+fn create_file_list(files: &[&String]) -> std::io::Result<(String, String)> {
+    // Write files, relative to their deepest common directory, to a temporary list for
+    // rsync's --files-from. Entries are NUL separated (--from0), so any file name is passed verbatim.
+    // Returns the list file and the common directory used as rsync source.
+    static FILE_LIST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let files = files
+        .iter()
+        .map(std::path::absolute)
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    let mut source_root = files
+        .first()
+        .and_then(|file| file.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    for file in &files {
+        while !file.starts_with(&source_root) {
+            source_root.pop();
+        }
+    }
+    let mut list = Vec::new();
+    for file in &files {
+        list.extend_from_slice(file.strip_prefix(&source_root).unwrap().as_os_str().as_encoded_bytes());
+        list.push(b'\0');
+    }
+    let list_file = env::temp_dir().join(format!(
+        "rsync_csv_files_{}_{}",
+        std::process::id(),
+        FILE_LIST_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::write(&list_file, list)?;
+    Ok((list_file.display().to_string(), source_root.display().to_string()))
+}
+
+fn rsync_to_host(
+    group: &SyncGroup,
+    file_list: Option<&(String, String)>,
+    dest_host: &str,
+    dest_dir: &str,
+    config: &Config,
+    transfer_state: &TransferState,
+) -> Result<(HostTransfer, u32), String> {
+    // Rsync a group to one destination host, retrying up to RSYNC_RETRIES times on failure.
+    // Returns the files rsync reported as transferred or up to date and the rsync error if all attempts failed,
+    // with the number of attempts made. After a failed attempt, files confirmed complete on the destination are
+    // reported as transferred and only the others are retried.
+    // With ATOMIC_PUBLISH, files land in a staging directory and are moved into place afterwards.
+    let remote_dir = if config.atomic_publish {
+        Path::new(dest_dir).join(&config.atomic_publish_staging_dir).display().to_string()
+    } else {
+        dest_dir.to_string()
+    };
+    if config.transfer_tool == TransferTool::Scp {
+        let (host_transfer, attempts) = scp_to_host(group, dest_host, dest_dir, &remote_dir, config, transfer_state);
+        return Ok((publish_host_transfer(group, host_transfer, dest_host, dest_dir, &remote_dir, config), attempts));
+    }
+    let mkdir_command = match &config.remote_dir_mode {
+        // mkdir -m only applies the mode to the directories named, so the table directory is named
+        // too when files are staged below it
+        Some(mode) if config.atomic_publish => {
+            format!("\"mkdir -m {} -p \"{}\" \"{}\" && rsync\"", mode, dest_dir, remote_dir)
+        }
+        Some(mode) => format!("\"mkdir -m {} -p \"{}\" && rsync\"", mode, remote_dir),
+        None => format!("\"mkdir -p \"{}\" && rsync\"", remote_dir),
+    };
+    // The options of -a, without -t when files should get their arrival time on the destination
+    let archive_flags = match config.rsync_times {
+        RsyncTimes::Preserve => "-rlptgoD",
+        RsyncTimes::Arrival => "-rlpgoD",
+    };
+    let mut rsync_options = vec![
+        format!("{}Lvz", archive_flags),
+        // Itemize unchanged files too, so every file rsync handled is listed in its output
+        "--itemize-changes".to_string(),
+        "--itemize-changes".to_string(),
+        "--partial-dir=tmp".to_string(),
+        "--timeout=10".to_string(),
+        format!("--rsync-path={}", mkdir_command),
+    ];
+    if let Some(remote_shell) = build_remote_shell(config, false) {
+        rsync_options.push(format!("-e {}", shell_quote(&remote_shell)));
+    }
+    // Files of the transfer are put in place together once all of them arrived
+    if config.rsync_delay_updates {
+        rsync_options.push("--delay-updates".to_string());
+    }
+    if let Some(chmod) = &config.rsync_chmod {
+        rsync_options.push(format!("--chmod={}", shell_quote(chmod)));
+    }
+    // Only the path of the password file is on the command line, never the password itself
+    if let Some(password_file) = &config.rsync_password_file {
+        rsync_options.push(format!("--password-file={}", shell_quote(password_file)));
+    }
+    // Files confirmed on the destination after a failed attempt, which are not sent again
+    let mut confirmed: HashSet<String> = HashSet::new();
+    let mut retry_count = 0;
+    loop {
+        let remaining_files: Vec<&String> = transfer_files(group)
+            .filter(|file| !confirmed.contains(Path::new(file).file_name().unwrap().to_str().unwrap()))
+            .collect();
+        // A retry after a partial transfer lists the remaining files in a file list of its own
+        let retry_file_list = match file_list {
+            Some(_) if !confirmed.is_empty() => match create_file_list(&remaining_files) {
+                Ok(retry_file_list) => Some(retry_file_list),
+                Err(e) => {
+                    error!("Error creating rsync file list for the retry, passing files as arguments: {:?}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let attempt_file_list = if confirmed.is_empty() { file_list } else { retry_file_list.as_ref() };
+        let mut attempt_options = rsync_options.clone();
+        let attempt_files: Vec<String> = match attempt_file_list {
+            // Files are placed directly in the remote directory, as when passed as arguments
+            Some((list_file, source_root)) => {
+                attempt_options.push("--no-relative".to_string());
+                attempt_options.push("--from0".to_string());
+                attempt_options.push(format!("--files-from={}", shell_quote(list_file)));
+                vec![shell_quote(source_root)]
+            }
+            None => remaining_files.iter().map(|file| format!("\"{}\"", file)).collect(),
+        };
+        let destination = format!("{}@{}:{}", config.dest_user, dest_host, remote_dir);
+        // Without bandwidth limit and progress, for a dry run checking the files after a failure
+        // Without -t, modification times always differ on the destination, so the dry run compares checksums
+        let dry_run_command = format!(
+            "{} --dry-run{} {} {} {}",
+            shell_quote(&config.rsync_bin),
+            if config.rsync_times == RsyncTimes::Arrival { " --checksum" } else { "" },
+            attempt_options.join(" "),
+            attempt_files.join(" "),
+            destination
+        );
+        if config.global_bwlimit > 0 {
+            // Share global bandwidth between transfers running when this attempt starts
+            let active_transfers = transfer_state.active_transfers.load(Ordering::SeqCst).max(1);
+            let bwlimit = (config.global_bwlimit / active_transfers as u64).max(1);
+            attempt_options.push(format!("--bwlimit={}", bwlimit));
+        }
+        if config.rsync_progress_interval_seconds > 0 {
+            attempt_options.push("--info=progress2".to_string());
+        }
+        let rsync_command = format!(
+            "{} {} {} {}",
+            shell_quote(&config.rsync_bin),
+            attempt_options.join(" "),
+            attempt_files.join(" "),
+            destination
+        );
+        info!("Running rsync command: {}", redact_command(&rsync_command, config));
+        let command = rsync_shell_command(&rsync_command, config);
+        let ssh_connection = SshConnection::acquire(config);
+        let command_output = if config.rsync_progress_interval_seconds > 0 {
+            run_with_progress(command, dest_host, dest_dir, config.rsync_progress_interval_seconds)
+        } else {
+            let mut command = command;
+            command.output()
+        };
+        drop(ssh_connection);
+        let output = match command_output {
+            Ok(output) => output,
+            Err(e) => {
+                error!("Failed to execute rsync command. Error: {}", e);
+                Err(format!("Failed to execute rsync command: {}", e))?
+            }
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let err_msg = String::from_utf8_lossy(&output.stderr);
+        let changed = parse_changed_files(&err_msg);
+        // Exit code 24: some source files vanished, the others were transferred.
+        // Exit code 23 with files changed while read is a partial transfer too when they are re-queued.
+        let completed = output.status.success()
+            || output.status.code() == Some(24)
+            || (output.status.code() == Some(23) && config.requeue_changed_files && !changed.is_empty());
+        // Exit code 11 (file I/O) or 12 (protocol stream, when the receiver dies) from a full disk, or 23 when
+        // single files failed to be written
+        let dest_full =
+            !completed && matches!(output.status.code(), Some(11 | 12 | 23)) && is_no_space_error(&err_msg);
+        if !completed && !parse_itemized_files(&stdout).is_empty() {
+            // Some files were sent before the failure. Those now up to date on the destination are confirmed.
+            let newly_confirmed = confirm_transferred_files(&dry_run_command, config);
+            if !newly_confirmed.is_empty() {
+                info!(
+                    "{} file(s) confirmed on {} despite the failure, they are not sent again",
+                    newly_confirmed.len(),
+                    dest_host
+                );
+            }
+            confirmed.extend(newly_confirmed);
+        }
+        if let Some((retry_list_file, _)) = &retry_file_list {
+            remove_file_if_exists(retry_list_file);
+        }
+        if output.status.success() {
+            info!("Success: {}", stdout);
+        } else if completed {
+            warn!("Partial transfer, some files vanished or changed: {}", err_msg);
+        } else {
+            error!("Error: {}", err_msg);
+        }
+        let all_confirmed = transfer_files(group)
+            .all(|file| confirmed.contains(Path::new(file).file_name().unwrap().to_str().unwrap()));
+        if !completed && !all_confirmed && retry_count < config.rsync_retries && !dest_full {
+            info!("Retrying rsync command...");
+            retry_count += 1;
+            continue;
+        }
+        let mut host_transfer = HostTransfer {
+            transferred: confirmed,
+            error: None,
+        };
+        if completed {
+            let mut transferred = parse_itemized_files(&stdout);
+            // Files whose update rsync discarded are itemized but did not arrive
+            transferred.retain(|file| !changed.contains(file));
+            transfer_state.changed_files.lock().unwrap().extend(changed);
+            host_transfer.transferred.extend(transferred);
+        } else if !all_confirmed && dest_full {
+            host_transfer.error = Some(report_dest_full(dest_host, dest_dir, &err_msg, config));
+        } else if !all_confirmed {
+            host_transfer.error = Some(err_msg.to_string());
+        }
+        return Ok((
+            publish_host_transfer(group, host_transfer, dest_host, dest_dir, &remote_dir, config),
+            retry_count + 1,
+        ));
+    }
+}
+
+fn publish_host_transfer(
+    group: &SyncGroup,
+    host_transfer: HostTransfer,
+    dest_host: &str,
+    dest_dir: &str,
+    remote_dir: &str,
+    config: &Config,
+) -> HostTransfer {
+    // With ATOMIC_PUBLISH, move the transferred files out of the staging directory. Nothing counts as
+    // transferred if publishing fails.
+    if !config.atomic_publish || host_transfer.transferred.is_empty() {
+        return host_transfer;
+    }
+    match publish_staged_files(group, &host_transfer.transferred, dest_host, dest_dir, remote_dir, config) {
+        Ok(()) => host_transfer,
+        Err(e) => HostTransfer {
+            transferred: HashSet::new(),
+            error: Some(e),
+        },
+    }
+}
+
+fn scp_to_host(
+    group: &SyncGroup,
+    dest_host: &str,
+    dest_dir: &str,
+    remote_dir: &str,
+    config: &Config,
+    transfer_state: &TransferState,
+) -> (HostTransfer, u32) {
+    // Copy a group to one destination host with scp, for destinations without rsync, retrying up to
+    // RSYNC_RETRIES times on failure. The remote directory is created over ssh first.
+    // scp reports no per-file result and can't resume, so every attempt copies all files again and either
+    // all files are transferred or none. Returns the transfer with the number of attempts made.
+    let mkdir_dirs = if config.atomic_publish { vec![dest_dir, remote_dir] } else { vec![remote_dir] };
+    let mkdir_dirs: Vec<String> = mkdir_dirs.into_iter().map(shell_quote).collect();
+    let mkdir_command = match &config.remote_dir_mode {
+        Some(mode) => format!("mkdir -m {} -p -- {}", mode, mkdir_dirs.join(" ")),
+        None => format!("mkdir -p -- {}", mkdir_dirs.join(" ")),
+    };
+    let files: Vec<&String> = transfer_files(group).collect();
+    let destination = format!("{}@{}:{}/", config.dest_user, dest_host, remote_dir);
+    let mut retry_count = 0;
+    loop {
+        let mut ssh_command = Command::new("ssh");
+        for option in ssh_options(config) {
+            ssh_command.arg("-o").arg(option);
+        }
+        run_as(&mut ssh_command, config);
+        ssh_command
+            .arg(format!("{}@{}", config.dest_user, dest_host))
+            .arg(&mkdir_command);
+        // -p keeps modification times like rsync -a, -C compresses like rsync -z
+        let mut scp_command = Command::new("scp");
+        if config.rsync_times == RsyncTimes::Preserve {
+            scp_command.arg("-p");
+        }
+        scp_command.arg("-C").arg("-q");
+        for option in ssh_options(config) {
+            scp_command.arg("-o").arg(option);
+        }
+        if config.global_bwlimit > 0 {
+            // scp limits in Kbit/s, GLOBAL_BWLIMIT is in KB/s like rsync --bwlimit
+            let active_transfers = transfer_state.active_transfers.load(Ordering::SeqCst).max(1);
+            let bwlimit = (config.global_bwlimit / active_transfers as u64).max(1);
+            scp_command.arg("-l").arg((bwlimit * 8).to_string());
+        }
+        run_as(&mut scp_command, config);
+        scp_command.args(&files).arg(&destination);
+        let redacted_ssh_options = build_remote_shell(config, true)
+            .map(|remote_shell| format!("{} ", remote_shell.trim_start_matches("ssh ")))
+            .unwrap_or_default();
+        info!(
+            "Running scp command: scp {}-C -q {}{} {}",
+            if config.rsync_times == RsyncTimes::Preserve { "-p " } else { "" },
+            redacted_ssh_options,
+            files.iter().map(|file| format!("\"{}\"", file)).collect::<Vec<String>>().join(" "),
+            destination
+        );
+        let ssh_connection = SshConnection::acquire(config);
+        let command_output = ssh_command.output().and_then(|output| {
+            if output.status.success() {
+                scp_command.output()
+            } else {
+                Ok(output)
+            }
+        });
+        drop(ssh_connection);
+        let err_msg = match command_output {
+            Ok(output) if output.status.success() => {
+                info!("Success: copied {} file(s) to {}:{}", files.len(), dest_host, remote_dir);
+                let transferred = files
+                    .iter()
+                    .map(|file| Path::new(file).file_name().unwrap().to_str().unwrap().to_string())
+                    .collect();
+                return (HostTransfer { transferred, error: None }, retry_count + 1);
+            }
+            Ok(output) => String::from_utf8_lossy(&output.stderr).to_string(),
+            Err(e) => format!("Failed to execute scp command. Error: {}", e),
+        };
+        error!("Error: {}", err_msg);
+        let dest_full = is_no_space_error(&err_msg);
+        if retry_count < config.rsync_retries && !dest_full {
+            info!("Retrying scp command...");
+            retry_count += 1;
+            continue;
+        }
+        let err_msg = if dest_full { report_dest_full(dest_host, dest_dir, &err_msg, config) } else { err_msg };
+        return (
+            HostTransfer {
+                transferred: HashSet::new(),
+                error: Some(err_msg),
+            },
+            retry_count + 1,
+        );
+    }
+}
+
+fn is_no_space_error(err_msg: &str) -> bool {
+    // Whether a transfer failed because the destination filesystem is full or the user's quota is used up
+    err_msg.contains("No space left on device") || err_msg.contains("Disk quota exceeded")
+}
+
+fn report_dest_full(dest_host: &str, dest_dir: &str, err_msg: &str, config: &Config) -> String {
+    // Log and alert that a destination ran out of space, which retrying won't fix, and return the failure
+    // reason of its files
+    error!(
+        "Destination {}:{} is out of space. Not retrying the transfer; free space on the destination. {}",
+        dest_host,
+        dest_dir,
+        err_msg.trim()
+    );
+    if let Some(webhook_url) = &config.dest_full_webhook_url {
+        let mut payload = serde_json::json!({
+            "status": "dest_full",
+            "dest_host": dest_host,
+            "dest_dir": dest_dir,
+            "reason": err_msg.trim(),
+        });
+        if let Some(hostname) = &config.source_hostname {
+            payload["host"] = serde_json::json!(hostname);
+        }
+        if let Some(batch_id) = current_batch_id() {
+            payload["batch_id"] = serde_json::json!(batch_id);
+        }
+        post_webhook(webhook_url, payload, "destination full");
+    }
+    format!("{}: {}", DEST_FULL_REASON, err_msg.trim())
+}
+
+struct HostTransfer {
+    // Names of the files rsync reported as transferred or up to date on the destination
+    transferred: HashSet<String>,
+    // The rsync error if the last attempt failed. Files confirmed despite the failure are still transferred.
+    error: Option<String>,
+}
+
+fn rsync_shell_command(rsync_command: &str, config: &Config) -> Command {
+    // Build the process running an rsync command line through sh
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(rsync_command);
+    run_as(&mut command, config);
+    // The secret is read at transfer time and handed to rsync through its environment
+    if let Some(password_env) = &config.rsync_password_env {
+        match env::var(password_env) {
+            Ok(password) => {
+                command.env("RSYNC_PASSWORD", password);
+            }
+            Err(_) => warn!("RSYNC_PASSWORD_ENV names {}, which is not set", password_env),
+        }
+    }
+    command
+}
+
+fn confirm_transferred_files(dry_run_command: &str, config: &Config) -> HashSet<String> {
+    // Run an rsync dry run of a failed transfer and return the files it reports as already up to date
+    // (".f" itemized lines), i.e. complete on the destination. Nothing is confirmed if the dry run fails.
+    info!("Checking transferred files: {}", redact_command(dry_run_command, config));
+    let ssh_connection = SshConnection::acquire(config);
+    let dry_run_output = rsync_shell_command(dry_run_command, config).output();
+    drop(ssh_connection);
+    match dry_run_output {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let up_to_date_lines: String = stdout
+                .lines()
+                .filter(|line| line.starts_with('.'))
+                .map(|line| format!("{}\n", line))
+                .collect();
+            parse_itemized_files(&up_to_date_lines)
+        }
+        Ok(output) => {
+            warn!(
+                "Failed to check transferred files: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            HashSet::new()
+        }
+        Err(e) => {
+            warn!("Failed to execute rsync command. Error: {}", e);
+            HashSet::new()
+        }
+    }
+}
+
+fn run_with_progress(
+    mut command: Command,
+    dest_host: &str,
+    dest_dir: &str,
+    progress_interval_seconds: u64,
+) -> std::io::Result<std::process::Output> {
+    // Run rsync with --info=progress2, logging its overall progress at most once per interval while
+    // it runs. Progress updates are dropped from the returned stdout, which keeps the itemized file list.
+    let mut child = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    let mut child_stderr = child.stderr.take().unwrap();
+    // Read stderr in the background so a full stderr pipe can't block rsync while stdout is read
+    let stderr_reader = thread::spawn(move || {
+        let mut stderr = Vec::new();
+        let _ = child_stderr.read_to_end(&mut stderr);
+        stderr
+    });
+    let mut reader = BufReader::new(child.stdout.take().unwrap());
+    let mut stdout: Vec<u8> = Vec::new();
+    let mut segment: Vec<u8> = Vec::new();
+    let mut last_progress_log = Instant::now();
+    // Progress updates end in a carriage return and rewrite the same terminal line
+    loop {
+        segment.clear();
+        let mut byte = [0u8];
+        while reader.read(&mut byte)? == 1 {
+            segment.push(byte[0]);
+            if byte[0] == b'\r' || byte[0] == b'\n' {
+                break;
+            }
+        }
+        if segment.is_empty() {
+            break;
+        }
+        let line = String::from_utf8_lossy(&segment);
+        let is_progress = line
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|percent| percent.ends_with('%'));
+        if !is_progress {
+            stdout.extend_from_slice(&segment);
+        } else if last_progress_log.elapsed().as_secs() >= progress_interval_seconds {
+            info!("Transfer progress to {}:{}: {}", dest_host, dest_dir, line.trim());
+            last_progress_log = Instant::now();
+        }
+    }
+    let status = child.wait()?;
+    let stderr = stderr_reader.join().unwrap_or_default();
+    Ok(std::process::Output { status, stdout, stderr })
+}
+
+struct SshConnection {
+    limited: bool,
+}
+
+impl SshConnection {
+    fn acquire(config: &Config) -> SshConnection {
+        // Wait for a free connection slot if MAX_SSH_CONNECTIONS is set. The slot is released on drop.
+        if config.max_ssh_connections == 0 {
+            return SshConnection { limited: false };
+        }
+        let (in_use, released) = &SSH_CONNECTIONS;
+        let mut in_use = in_use.lock().unwrap();
+        if *in_use >= config.max_ssh_connections {
+            info!(
+                "Waiting for a free ssh connection slot ({} of {} in use)",
+                *in_use, config.max_ssh_connections
+            );
+        }
+        while *in_use >= config.max_ssh_connections {
+            in_use = released.wait(in_use).unwrap();
+        }
+        *in_use += 1;
+        SshConnection { limited: true }
+    }
+}
+
+impl Drop for SshConnection {
+    fn drop(&mut self) {
+        if self.limited {
+            let (in_use, released) = &SSH_CONNECTIONS;
+            *in_use.lock().unwrap() -= 1;
+            released.notify_one();
+        }
+    }
+}
+
+fn publish_staged_files(
+    group: &SyncGroup,
+    transferred: &HashSet<String>,
+    dest_host: &str,
+    dest_dir: &str,
+    staging_dir: &str,
+    config: &Config,
+) -> Result<(), String> {
+    // Move transferred files from the remote staging directory into dest_dir over ssh.
+    // Both are on the same filesystem, so each mv is an atomic rename and loaders never see partial files.
+    // Companion files are published before the data files they describe.
+    let data_files: HashSet<&str> = group
+        .upload_files
+        .iter()
+        .filter_map(|file| Path::new(file).file_name().and_then(|name| name.to_str()))
+        .collect();
+    let mut publish_files: Vec<&String> = transferred.iter().collect();
+    publish_files.sort_by_key(|file| (data_files.contains(file.as_str()), *file));
+    if publish_files.is_empty() {
+        return Ok(());
+    }
+    // Paths may be relative to the remote home directory, so no cd
+    let staged_files: Vec<String> = publish_files
+        .iter()
+        .map(|file| shell_quote(&Path::new(staging_dir).join(file).display().to_string()))
+        .collect();
+    let publish_command = if group.dest_names.is_empty() {
+        format!("mv -f -- {} {}/", staged_files.join(" "), shell_quote(dest_dir))
+    } else {
+        // With DEST_FILE_NAME each file is moved to its own destination name, in the same order
+        publish_files
+            .iter()
+            .zip(&staged_files)
+            .map(|(file, staged_file)| {
+                let dest_file = Path::new(dest_dir).join(published_name(file, group));
+                format!("mv -f -- {} {}", staged_file, shell_quote(&dest_file.display().to_string()))
+            })
+            .collect::<Vec<String>>()
+            .join(" && ")
+    };
+    info!("Publishing staged files on {}: {}", dest_host, publish_command);
+    let mut ssh_command = Command::new("ssh");
+    for option in ssh_options(config) {
+        ssh_command.arg("-o").arg(option);
+    }
+    run_as(&mut ssh_command, config);
+    let ssh_connection = SshConnection::acquire(config);
+    let publish_output = ssh_command
+        .arg(format!("{}@{}", config.dest_user, dest_host))
+        .arg(&publish_command)
+        .output();
+    drop(ssh_connection);
+    match publish_output {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => {
+            let err_msg = format!(
+                "Failed to publish staged files: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            error!("{}", err_msg);
+            Err(err_msg)
+        }
+        Err(e) => {
+            error!("Failed to execute ssh command. Error: {}", e);
+            Err(format!("Failed to execute ssh command: {}", e))
+        }
+    }
+}
+
+fn chown_dest_files(
+    group: &SyncGroup,
+    transferred: &HashSet<String>,
+    dest_host: &str,
+    dest_dir: &str,
+    owner: &str,
+    config: &Config,
+) {
+    // Give the files transferred to dest_dir the table's TABLE_DEST_OWNER by running DEST_CHOWN_COMMAND over ssh.
+    // The files are already in place, so a failure is only logged.
+    if transferred.is_empty() {
+        return;
+    }
+    let mut dest_files: Vec<String> = transferred
+        .iter()
+        .map(|file| {
+            let dest_name = if group.dest_names.is_empty() { file.clone() } else { published_name(file, group) };
+            shell_quote(&Path::new(dest_dir).join(dest_name).display().to_string())
+        })
+        .collect();
+    dest_files.sort();
+    let chown_command = config
+        .dest_chown_command
+        .replace("{owner}", &shell_quote(owner))
+        .replace("{dest_dir}", &shell_quote(dest_dir))
+        .replace("{files}", &dest_files.join(" "));
+    info!("Changing owner of {} file(s) on {}: {}", dest_files.len(), dest_host, chown_command);
+    let mut ssh_command = Command::new("ssh");
+    for option in ssh_options(config) {
+        ssh_command.arg("-o").arg(option);
+    }
+    run_as(&mut ssh_command, config);
+    let ssh_connection = SshConnection::acquire(config);
+    let chown_output = ssh_command
+        .arg(format!("{}@{}", config.dest_user, dest_host))
+        .arg(&chown_command)
+        .output();
+    drop(ssh_connection);
+    match chown_output {
+        Ok(output) if output.status.success() => (),
+        Ok(output) => warn!(
+            "Failed to change owner of files in {} on {} to {}: {}",
+            dest_dir,
+            dest_host,
+            owner,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => warn!("Failed to execute ssh command. Error: {}", e),
+    }
+}
+
+fn published_name(file_name: &str, group: &SyncGroup) -> String {
+    // Remote name of a transferred file with DEST_FILE_NAME. Companions named after a data file (e.g. its
+    // .metadata and .md5 files) follow its new name, other files keep theirs.
+    for (upload_file, dest_name) in group.upload_files.iter().zip(&group.dest_names) {
+        let upload_file_basename = Path::new(upload_file).file_name().unwrap().to_str().unwrap();
+        if let Some(companion_extension) = file_name.strip_prefix(upload_file_basename) {
+            if companion_extension.is_empty() || companion_extension.starts_with('.') {
+                return format!("{}{}", dest_name, companion_extension);
+            }
+        }
+    }
+    file_name.to_string()
+}
+
+fn render_dest_file_name(dest_file_name: &str, src_file: &str, table_name: &str, config: &Config) -> String {
+    // Fill the DEST_FILE_NAME tokens for a suffixed source file
+    let file_name = Path::new(src_file).file_name().unwrap().to_string_lossy().to_string();
+    let (basename, timestamp) = split_suffixed_name(&file_name, &config.file_suffix).unwrap_or_else(|| {
+        warn!("No FILE_SUFFIX timestamp found in {:?}, using it as {{basename}}", file_name);
+        (file_name.clone(), String::new())
+    });
+    dest_file_name
+        .replace("{basename}", &basename)
+        .replace("{table}", table_name)
+        .replace("{timestamp}", &timestamp)
+}
+
+fn split_suffixed_name(file_name: &str, file_suffix: &str) -> Option<(String, String)> {
+    // Split a name produced by suffix_file_name, "<stem>_<timestamp>[_<count>][.<extension>]", into the
+    // original file name and the timestamp (with the collision count, if any). The timestamp is the
+    // rightmost part of the stem, starting after an underscore, that parses with the FILE_SUFFIX format.
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (file_name, None),
+    };
+    let matches_suffix = |value: &str| {
+        let mut parsed = chrono::format::Parsed::new();
+        chrono::format::parse(&mut parsed, value, chrono::format::StrftimeItems::new(file_suffix)).is_ok()
+    };
+    stem.rmatch_indices('_').find_map(|(index, _)| {
+        let timestamp = &stem[index + 1..];
+        let without_count = match timestamp.rsplit_once('_') {
+            Some((without_count, count)) if count.chars().all(|c| c.is_ascii_digit()) => Some(without_count),
+            _ => None,
+        };
+        if !matches_suffix(timestamp) && !without_count.is_some_and(matches_suffix) {
+            return None;
+        }
+        let basename = match extension {
+            Some(extension) => format!("{}.{}", &stem[..index], extension),
+            None => stem[..index].to_string(),
+        };
+        Some((basename, timestamp.to_string()))
+    })
+}
+
+fn parse_changed_files(rsync_errors: &str) -> HashSet<String> {
+    // Collect the names of files rsync reports as vanished or changed while they were read, from lines like
+    // "file has vanished: \"/src/data.csv\"", "WARNING: data.csv failed verification -- update discarded"
+    // or "rsync: \"/src/data.csv\": file changed as we read it"
+    rsync_errors
+        .lines()
+        .filter_map(|line| {
+            if let Some((_, path)) = line.split_once("file has vanished: ") {
+                Some(path.trim().trim_matches('"'))
+            } else if let Some((prefix, _)) = line.split_once(": file changed as we read it") {
+                let path = prefix.rsplit_once(": ").map_or(prefix, |(_, path)| path);
+                Some(path.trim().trim_matches('"'))
+            } else if let Some((prefix, _)) = line.split_once(" failed verification") {
+                prefix.split_once(": ").map(|(_, path)| path.trim())
+            } else {
+                None
+            }
+        })
+        .filter_map(|path| Path::new(path).file_name())
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .collect()
+}
+
+fn parse_itemized_files(rsync_output: &str) -> HashSet<String> {
+    // Collect the names of regular files from rsync --itemize-changes output lines, made of the
+    // 11 character YXcstpoguax change summary and the file name, such as
+    // "<f+++++++++ data.csv" (sent) or ".f          data.csv" (already up to date)
+    rsync_output
+        .lines()
+        .filter(|line| {
+            line.starts_with(['<', '>', 'c', 'h', '.'])
+                && line[1..].starts_with('f')
+                && line.as_bytes().get(11) == Some(&b' ')
+        })
+        .filter_map(|line| line.get(12..))
+        .map(str::to_string)
+        .collect()
+}
+
+fn create_batch_manifest(
+    table_name: &str,
+    group: &SyncGroup,
+    config: &Config,
+) -> std::io::Result<String> {
+    // Write a manifest listing every source file of the batch with its sha256, size and row count.
+    // It is written to a temporary directory so the watcher does not pick it up as a csv file.
+    static MANIFEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let manifest_dir = env::temp_dir().join(format!(
+        "rsync_csv_{}_{}",
+        std::process::id(),
+        MANIFEST_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&manifest_dir)?;
+    let manifest_name = config
+        .batch_manifest_name
+        .replace("{table}", table_name)
+        .replace(
+            "{timestamp}",
+            &format_timestamp(chrono::Utc::now(), &config.file_suffix, config.metadata_timezone),
+        );
+    let mut manifest_entries: Vec<(String, String, u64, u64)> = Vec::new();
+    for (index, upload_file) in group.upload_files.iter().enumerate() {
+        // Files are listed under their remote name
+        let upload_file_basename = match group.dest_names.get(index) {
+            Some(dest_name) => dest_name.clone(),
+            None => Path::new(upload_file).file_name().unwrap().to_string_lossy().to_string(),
+        };
+        manifest_entries.push((
+            upload_file_basename,
+            sha256_hex(upload_file)?,
+            fs::metadata(upload_file)?.len(),
+            count_data_rows(upload_file, config)?,
+        ));
+    }
+    let manifest_data = match config.batch_manifest_format {
+        ManifestFormat::Csv => {
+            let mut manifest_data = "filename,sha256,bytes,rows\n".to_string();
+            for (filename, sha256, bytes, rows) in &manifest_entries {
+                manifest_data
+                    .push_str(&format!("{},{},{},{}\n", csv_field(filename), sha256, bytes, rows));
+            }
+            manifest_data
+        }
+        ManifestFormat::Json => {
+            let manifest_json: Vec<serde_json::Value> = manifest_entries
+                .iter()
+                .map(|(filename, sha256, bytes, rows)| {
+                    serde_json::json!({
+                        "filename": filename,
+                        "sha256": sha256,
+                        "bytes": bytes,
+                        "rows": rows,
+                    })
+                })
+                .collect();
+            serde_json::to_string_pretty(&manifest_json).unwrap()
+        }
+    };
+    let manifest_file_path = manifest_dir.join(manifest_name);
+    fs::write(&manifest_file_path, manifest_data)?;
+    info!("Batch manifest {:?} created for table {}", manifest_file_path, table_name);
+    Ok(manifest_file_path.to_str().unwrap().to_string())
+}
+
+struct RollupEntry {
+    time: String,
+    table_name: String,
+    dest_dir: String,
+    manifest: String,
+    sha256: String,
+}
+
+fn record_rollup_entry(table_name: &str, dest_dir: &str, manifest_file: &str, config: &Config) {
+    // Append a delivered batch manifest and its hash to ROLLUP_ENTRIES_FILE for the next rollup manifest.
+    // Each line is "<time>\t<table>\t<dest_dir>\t<manifest>\t<sha256>".
+    let sha256 = match sha256_hex(manifest_file) {
+        Ok(sha256) => sha256,
+        Err(e) => {
+            error!("Error hashing batch manifest {} for the rollup manifest: {:?}", manifest_file, e);
+            return;
+        }
+    };
+    let time = format_timestamp(chrono::Utc::now(), &config.metadata_time_format, config.metadata_timezone);
+    let manifest = Path::new(manifest_file).file_name().unwrap().to_string_lossy().to_string();
+    let _guard = ROLLUP_ENTRIES_LOCK.lock().unwrap();
+    let result = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&config.rollup_entries_file)
+        .and_then(|mut entries| {
+            writeln!(entries, "{}\t{}\t{}\t{}\t{}", time, table_name, dest_dir, manifest, sha256)
+        });
+    if let Err(e) = result {
+        error!(
+            "Failed to record batch manifest {} in {}, it is left out of the rollup manifest. Error: {}",
+            manifest, config.rollup_entries_file, e
+        );
+    }
+}
+
+fn read_rollup_entries(rollup_entries_file: &str) -> std::io::Result<Vec<RollupEntry>> {
+    // Read the entries recorded in ROLLUP_ENTRIES_FILE, none if it does not exist
+    let entries = match fs::read_to_string(rollup_entries_file) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(entries
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match line.split('\t').collect::<Vec<&str>>()[..] {
+            [time, table_name, dest_dir, manifest, sha256] => Some(RollupEntry {
+                time: time.to_string(),
+                table_name: table_name.to_string(),
+                dest_dir: dest_dir.to_string(),
+                manifest: manifest.to_string(),
+                sha256: sha256.to_string(),
+            }),
+            _ => {
+                warn!("Dropping invalid rollup entry {:?}", line);
+                None
+            }
+        })
+        .collect())
+}
+
+fn remove_rollup_entries(rollup_entries_file: &str, count: usize) -> std::io::Result<()> {
+    // Remove the first count entries, which were rolled up, keeping entries recorded since they were read.
+    // The file is replaced atomically so an interrupted rewrite never loses entries.
+    let _guard = ROLLUP_ENTRIES_LOCK.lock().unwrap();
+    let entries = fs::read_to_string(rollup_entries_file)?;
+    let remaining: Vec<&str> = entries.lines().filter(|line| !line.trim().is_empty()).skip(count).collect();
+    if remaining.is_empty() {
+        return fs::remove_file(rollup_entries_file);
+    }
+    let tmp_file = format!("{}.tmp", rollup_entries_file);
+    fs::write(&tmp_file, format!("{}\n", remaining.join("\n")))?;
+    fs::rename(&tmp_file, rollup_entries_file)
+}
+
+fn rollup_due(times: &[chrono::NaiveTime], last_check: chrono::NaiveDateTime, now: chrono::NaiveDateTime) -> bool {
+    // A rollup is due when one of the ROLLUP_MANIFEST_TIMES passed since the last check
+    [last_check.date(), now.date()].iter().any(|date| {
+        times.iter().any(|time| {
+            let rollup_time = date.and_time(*time);
+            last_check < rollup_time && rollup_time <= now
+        })
+    })
+}
+
+fn transfer_rollup_manifest(config: &Config) {
+    // Write the batch manifests delivered since the last rollup, with their hashes, to a rollup manifest and
+    // transfer it to every destination host. The entries are kept for the next rollup if the transfer fails.
+    let entries = {
+        let _guard = ROLLUP_ENTRIES_LOCK.lock().unwrap();
+        read_rollup_entries(&config.rollup_entries_file)
+    };
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to read rollup entries {}: {}", config.rollup_entries_file, e);
+            return;
+        }
+    };
+    if entries.is_empty() {
+        info!("No batch manifests since the last rollup, skipping the rollup manifest");
+        return;
+    }
+    let now = chrono::Utc::now();
+    let rollup_name = config
+        .rollup_manifest_name
+        .replace("{date}", &format_timestamp(now, "%Y-%m-%d", config.metadata_timezone))
+        .replace("{timestamp}", &format_timestamp(now, &config.file_suffix, config.metadata_timezone));
+    let rollup_data = match config.batch_manifest_format {
+        ManifestFormat::Csv => {
+            let mut rollup_data = "time,table,dest_dir,manifest,sha256\n".to_string();
+            for entry in &entries {
+                rollup_data.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    csv_field(&entry.time),
+                    csv_field(&entry.table_name),
+                    csv_field(&entry.dest_dir),
+                    csv_field(&entry.manifest),
+                    entry.sha256
+                ));
+            }
+            rollup_data
+        }
+        ManifestFormat::Json => {
+            let rollup_json: Vec<serde_json::Value> = entries
+                .iter()
+                .map(|entry| {
+                    serde_json::json!({
+                        "time": entry.time,
+                        "table": entry.table_name,
+                        "dest_dir": entry.dest_dir,
+                        "manifest": entry.manifest,
+                        "sha256": entry.sha256,
+                    })
+                })
+                .collect();
+            serde_json::to_string_pretty(&rollup_json).unwrap()
+        }
+    };
+    // Written to a temporary directory so the watcher does not pick it up
+    let rollup_dir = env::temp_dir().join(format!("rsync_csv_rollup_{}", std::process::id()));
+    let rollup_file = rollup_dir.join(&rollup_name);
+    if let Err(e) = fs::create_dir_all(&rollup_dir).and_then(|_| fs::write(&rollup_file, rollup_data)) {
+        error!("Error writing rollup manifest {:?}: {:?}", rollup_file, e);
+        return;
+    }
+    let group = SyncGroup {
+        batch_files: vec![rollup_file.display().to_string()],
+        ..Default::default()
+    };
+    let transfer_state = TransferState {
+        summary: Mutex::new(BatchSummary::default()),
+        active_transfers: AtomicUsize::new(0),
+        changed_files: Mutex::new(HashSet::new()),
+        requeued_files: Mutex::new(Vec::new()),
+    };
+    let dest_dir = config.rollup_manifest_dir.as_deref().unwrap_or(&config.dest_dir);
+    let mut failed_hosts: Vec<String> = Vec::new();
+    for dest_host in std::iter::once(&config.dest_host).chain(&config.fanout_hosts) {
+        match rsync_to_host(&group, None, dest_host, dest_dir, config, &transfer_state) {
+            Ok((host_transfer, _)) if host_transfer.transferred.contains(&rollup_name) => (),
+            Ok((HostTransfer { error: None, .. }, _)) => {
+                failed_hosts.push(format!("{}: File was not transferred by rsync", dest_host))
+            }
+            Ok((HostTransfer { error: Some(e), .. }, _)) | Err(e) => {
+                failed_hosts.push(format!("{}: {}", dest_host, e.trim()))
+            }
+        }
+    }
+    remove_group_temp_files(&group);
+    if failed_hosts.is_empty() {
+        info!("Transferred rollup manifest {} listing {} batch manifest(s)", rollup_name, entries.len());
+        if let Err(e) = remove_rollup_entries(&config.rollup_entries_file, entries.len()) {
+            error!(
+                "Failed to remove rolled up entries from {}, they will be rolled up again. Error: {}",
+                config.rollup_entries_file, e
+            );
+        }
+    } else {
+        error!(
+            "Transfer of rollup manifest {} failed, keeping its entries for the next rollup. {}",
+            rollup_name,
+            failed_hosts.join("; ")
+        );
+    }
+}
+
+fn sha256_hex(file_path: &str) -> std::io::Result<String> {
+    // Compute sha256 hex digest with sha256sum, which streams the file
+    let output = Command::new("sha256sum").arg(file_path).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "sha256sum failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+fn count_data_rows(file_path: &str, config: &Config) -> std::io::Result<u64> {
+    // Count non-empty lines after the header row
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut line: Vec<u8> = Vec::new();
+    let mut line_index = 0;
+    let mut rows = 0;
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if line_index > config.header_row_index && !line.trim_ascii().is_empty() {
+            rows += 1;
+        }
+        line_index += 1;
+        line.clear();
+    }
+    Ok(rows)
+}
+
+fn csv_field(value: &str) -> String {
+    // Quote csv field if it contains a delimiter, quote or newline
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn ssh_options(config: &Config) -> Vec<String> {
+    // Collect ssh -o options shared by the rsync remote shell and the control master connection
+    let mut ssh_options: Vec<String> = Vec::new();
+    if let Some(proxy_command) = &config.ssh_proxy_command {
+        ssh_options.push(format!("ProxyCommand={}", proxy_command));
+    }
+    if let Some(control_path) = &config.ssh_control_path {
+        ssh_options.push("ControlMaster=auto".to_string());
+        ssh_options.push(format!("ControlPath={}", control_path));
+        ssh_options.push(format!("ControlPersist={}", config.ssh_control_persist));
+    }
+    ssh_options
+}
+
+fn build_remote_shell(config: &Config, redact: bool) -> Option<String> {
+    // Build the remote shell command passed to rsync -e. None keeps rsync's default remote shell.
+    // The ssh options are appended to ssh or an ssh based RSYNC_RSH, any other RSYNC_RSH is used as is.
+    // With redact, values of sensitive options are masked for logging.
+    let remote_shell = config.rsync_rsh.as_deref().unwrap_or("ssh");
+    let ssh_options = ssh_options(config);
+    if ssh_options.is_empty() || !is_ssh_command(remote_shell) {
+        return config.rsync_rsh.clone();
+    }
+    let ssh_options: Vec<String> = ssh_options
+        .iter()
+        .map(|option| match option.split_once('=') {
+            Some((key, _))
+                if redact
+                    && config
+                        .log_redact_keys
+                        .iter()
+                        .any(|redact_key| key.to_lowercase().contains(redact_key.as_str())) =>
+            {
+                format!("{}=***", key)
+            }
+            _ => option.to_string(),
+        })
+        .map(|option| format!("-o \"{}\"", option))
+        .collect();
+    Some(format!("{} {}", remote_shell, ssh_options.join(" ")))
+}
+
+fn is_ssh_command(remote_shell: &str) -> bool {
+    // Whether a remote shell command runs ssh, e.g. "ssh -p 2222" or "/usr/local/bin/ssh"
+    remote_shell
+        .split_whitespace()
+        .next()
+        .and_then(|program| Path::new(program).file_name())
+        .is_some_and(|program| program == "ssh")
+}
+
+fn redact_command(rsync_command: &str, config: &Config) -> String {
+    // Mask sensitive ssh options of an rsync command line before it is logged.
+    // Passwords are never on the command line: only the path of RSYNC_PASSWORD_FILE is, and
+    // RSYNC_PASSWORD_ENV is passed through the environment.
+    match (build_remote_shell(config, false), build_remote_shell(config, true)) {
+        (Some(remote_shell), Some(redacted_remote_shell)) => rsync_command.replacen(
+            &shell_quote(&remote_shell),
+            &shell_quote(&redacted_remote_shell),
+            1,
+        ),
+        _ => rsync_command.to_string(),
+    }
+}
+
+fn start_ssh_control_master(config: &Config) {
+    // Establish the multiplexed ssh connection reused by all rsync transfers
+    if config.ssh_control_path.is_none() {
+        return;
+    }
+    let mut ssh_command = Command::new("ssh");
+    for option in ssh_options(config) {
+        ssh_command.arg("-o").arg(option);
+    }
+    run_as(&mut ssh_command, config);
+    // -f backgrounds ssh after authentication, -N runs no remote command
+    ssh_command
+        .arg("-f")
+        .arg("-N")
+        .arg(format!("{}@{}", config.dest_user, config.dest_host));
+    match ssh_command.output() {
+        Ok(output) if output.status.success() => info!("SSH control master connection established."),
+        Ok(output) => error!(
+            "Failed to establish SSH control master connection. Transfers will connect on demand. Error: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => error!("Failed to execute ssh command. Error: {}", e),
+    }
+}
+
+fn stop_ssh_control_master(config: &Config) {
+    // Tear down the multiplexed ssh connection
+    if let Some(control_path) = &config.ssh_control_path {
+        let mut ssh_command = Command::new("ssh");
+        run_as(&mut ssh_command, config);
+        match ssh_command
+            .arg("-o")
+            .arg(format!("ControlPath={}", control_path))
+            .arg("-O")
+            .arg("exit")
+            .arg(format!("{}@{}", config.dest_user, config.dest_host))
+            .output()
+        {
+            Ok(output) if output.status.success() => info!("SSH control master connection closed."),
+            Ok(output) => debug!(
+                "SSH control master was not running: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => error!("Failed to execute ssh command. Error: {}", e),
+        }
+    }
+}
+
+fn run_as(command: &mut Command, config: &Config) {
+    // Run a transfer process as RUN_AS_USER and RUN_AS_GROUP, dropping root privileges before exec
+    if let Some(uid) = config.run_as_uid {
+        command.uid(uid);
+    }
+    if let Some(gid) = config.run_as_gid {
+        command.gid(gid);
+    }
+}
+
+fn resolve_id(id_args: &[&str], name: &str) -> Result<u32, String> {
+    // Resolve a user name or uid to its numeric uid (-u) or primary gid (-g) with the id command
+    let output = Command::new("id")
+        .args(id_args)
+        .arg(name)
+        .output()
+        .map_err(|e| format!("Failed to execute id command: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| format!("Unexpected id output: {}", e))
+}
+
+fn resolve_group(name: &str) -> Result<u32, String> {
+    // Resolve a group name (or numeric id) to its numeric id with getent
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(gid);
+    }
+    let output = Command::new("getent")
+        .arg("group")
+        .arg(name)
+        .output()
+        .map_err(|e| format!("Failed to execute getent command: {}", e))?;
+    // Entries are "name:password:gid:members"
+    String::from_utf8_lossy(&output.stdout)
+        .split(':')
+        .nth(2)
+        .and_then(|gid| gid.trim().parse::<u32>().ok())
+        .ok_or_else(|| format!("group {:?} not found", name))
+}
+
+fn shell_quote(value: &str) -> String {
+    // Single quote value for sh, escaping embedded single quotes
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn find_executable(program: &str) -> Option<PathBuf> {
+    // Resolve program to an executable path, searching PATH if it has no directory component
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .map(|attr| attr.is_file() && attr.mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    if program.contains('/') {
+        return Some(PathBuf::from(program)).filter(|path| is_executable(path));
+    }
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|path| is_executable(path))
+    })
+}
+
+fn load_env_vars() -> Config {
+    // Load environment variables and set rsync src and dest paths
+    load_dotenv();
+    let mut config = Config {
+        src_dirs: env_list(&env_value("SOURCE_DIR").unwrap()),
+        dest_user: env_value("DEST_USER").unwrap(),
+        dest_host: env_value("DEST_HOST").unwrap(),
+        dest_dir: env_value("DEST_DIR").unwrap(),
+        template_dirs: env_list(&env_var_or("TEMPLATE_DIR", String::new())),
+        template_manifest: env_var_opt("TEMPLATE_MANIFEST"),
+        file_suffix: env_value("FILE_SUFFIX").unwrap(),
+        csv_event_wait_seconds: env_value("CSV_EVENT_WAIT_SECONDS")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap(),
+        csv_event_upper_limit: env_value("CSV_EVENT_UPPER_LIMIT")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap(),
+        min_file_age_seconds: env_var_or("MIN_FILE_AGE_SECONDS", 0),
+        metadata_time_format: env_var_or(
+            "METADATA_TIME_FORMAT",
+            "%Y-%m-%d %H:%M:%S".to_string(),
+        ),
+        metadata_timezone: env_var_or("METADATA_TIMEZONE", TimestampZone::Local),
+        sniff_extensionless: env_var_or("SNIFF_EXTENSIONLESS", false),
+        max_pending_events: env_var_or("MAX_PENDING_EVENTS", 0),
+        header_row_index: env_var_or("HEADER_ROW_INDEX", 0),
+        dest_subdir_map: env_list(&env_var_or("DEST_SUBDIR_MAP", String::new()))
+            .iter()
+            .map(|mapping| match mapping.split_once('=') {
+                Some((src_dir, prefix)) => (src_dir.trim().to_string(), prefix.trim().to_string()),
+                None => panic!("Invalid DEST_SUBDIR_MAP entry {:?}, expected SRC_DIR=PREFIX", mapping),
+            })
+            .collect(),
+        archive_dir: env_var_opt("ARCHIVE_DIR"),
+        archive_metadata: env_var_or("ARCHIVE_METADATA", false),
+        archive_mode: env_var_or("ARCHIVE_MODE", ArchiveMode::Move),
+        strict_column_count: env_var_or("STRICT_COLUMN_COUNT", false),
+        ssh_proxy_command: env_var_opt("SSH_PROXY_COMMAND"),
+        generate_md5_sidecar: env_var_or("GENERATE_MD5_SIDECAR", false),
+        upload_log_format: env_var_or("UPLOAD_LOG_FORMAT", UploadLogFormat::Text),
+        header_match_mode: env_var_or("HEADER_MATCH_MODE", HeaderMatchMode::Exact),
+        prefix_match_tables: env_list(&env_var_or("PREFIX_MATCH_TABLES", String::new())),
+        rsync_parallelism: env_var_or("RSYNC_PARALLELISM", 1),
+        inter_transfer_delay_seconds: env_var_or("INTER_TRANSFER_DELAY_SECONDS", 0),
+        global_bwlimit: env_var_or("GLOBAL_BWLIMIT", 0),
+        ssh_control_path: env_var_opt("SSH_CONTROL_PATH"),
+        ssh_control_persist: env_var_or("SSH_CONTROL_PERSIST", "10m".to_string()),
+        batch_manifest: env_var_or("BATCH_MANIFEST", false),
+        batch_manifest_name: env_var_or("BATCH_MANIFEST_NAME", "manifest.csv".to_string()),
+        batch_manifest_format: env_var_or("BATCH_MANIFEST_FORMAT", ManifestFormat::Csv),
+        rollup_manifest: env_var_or("ROLLUP_MANIFEST", false),
+        rollup_manifest_name: env_var_or("ROLLUP_MANIFEST_NAME", "rollup_{date}.csv".to_string()),
+        rollup_manifest_dir: env_var_opt("ROLLUP_MANIFEST_DIR"),
+        rollup_manifest_times: env_list(&env_var_or("ROLLUP_MANIFEST_TIMES", "00:00".to_string()))
+            .iter()
+            .map(|time| {
+                chrono::NaiveTime::parse_from_str(time, "%H:%M")
+                    .unwrap_or_else(|e| panic!("Invalid ROLLUP_MANIFEST_TIMES entry {:?}: {}", time, e))
+            })
+            .collect(),
+        rollup_entries_file: env_var_or("ROLLUP_ENTRIES_FILE", "rollup_entries.txt".to_string()),
+        source_tag: env_var_or("SOURCE_TAG", SourceTag::None),
+        ready_subdir: env_var_opt("READY_SUBDIR"),
+        header_match_retries: env_var_or("HEADER_MATCH_RETRIES", 0),
+        header_match_retry_interval_ms: env_var_or("HEADER_MATCH_RETRY_INTERVAL_MS", 500),
+        rsync_chmod: env_var_opt("RSYNC_CHMOD"),
+        max_file_bytes: env_var_or("MAX_FILE_BYTES", 0),
+        delete_delay_seconds: env_var_or("DELETE_DELAY_SECONDS", 0),
+        delete_queue_file: env_var_or("DELETE_QUEUE_FILE", "delete_queue.txt".to_string()),
+        fanout_hosts: env_list(&env_var_or("FANOUT_HOSTS", String::new())),
+        fanout_policy: env_var_or("FANOUT_POLICY", FanoutPolicy::All),
+        transfer_jitter_max_ms: env_var_or("TRANSFER_JITTER_MAX_MS", 0),
+        trigger_on: env_var_or("TRIGGER_ON", TriggerOn::Both),
+        column_rename_map: env_list(&env_var_or("COLUMN_RENAME_MAP", String::new()))
+            .iter()
+            .map(|mapping| {
+                match mapping.split_once('=').and_then(|(column, new_name)| {
+                    column.split_once('.').map(|(table_name, old_name)| (table_name, old_name, new_name))
+                }) {
+                    Some((table_name, old_name, new_name)) => (
+                        table_name.trim().to_string(),
+                        old_name.trim().to_string(),
+                        new_name.trim().to_string(),
+                    ),
+                    None => panic!("Invalid COLUMN_RENAME_MAP entry {:?}, expected TABLE.COLUMN=NEW_NAME", mapping),
+                }
+            })
+            .collect(),
+        rsync_password_file: env_var_opt("RSYNC_PASSWORD_FILE"),
+        rsync_password_env: env_var_opt("RSYNC_PASSWORD_ENV"),
+        log_redact_keys: DEFAULT_LOG_REDACT_KEYS
+            .iter()
+            .map(|key| key.to_string())
+            .chain(env_list(&env_var_or("LOG_REDACT_KEYS", String::new())))
+            .map(|key| key.to_lowercase())
+            .collect(),
+        source_hostname: env_var_or("INCLUDE_HOSTNAME", false).then(local_hostname),
+        atomic_publish: env_var_or("ATOMIC_PUBLISH", false),
+        atomic_publish_staging_dir: env_var_or("ATOMIC_PUBLISH_STAGING_DIR", ".rsync_csv_staging".to_string()),
+        dest_file_name: env_var_opt("DEST_FILE_NAME"),
+        unmatched_is_error: env_var_or("UNMATCHED_IS_ERROR", false),
+        per_table_debounce: env_var_or("PER_TABLE_DEBOUNCE", false),
+        max_ssh_connections: env_var_or("MAX_SSH_CONNECTIONS", 0),
+        header_drift_threshold: env_var_or("HEADER_DRIFT_THRESHOLD", 0.5),
+        respect_writer_locks: env_var_or("RESPECT_WRITER_LOCKS", false),
+        skip_empty_tables: env_var_or("SKIP_EMPTY_TABLES", false),
+        empty_table_action: env_var_or("EMPTY_TABLE_ACTION", EmptyTableAction::Keep),
+        empty_table_quarantine_dir: env_var_opt("EMPTY_TABLE_QUARANTINE_DIR"),
+        allowed_hours: env_var_opt("ALLOWED_HOURS").map(|allowed_hours| {
+            allowed_hours
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid value for ALLOWED_HOURS: {}", e))
+        }),
+        pause_file: env_var_opt("PAUSE_FILE"),
+        rsync_files_from: env_var_or("RSYNC_FILES_FROM", false),
+        success_webhook_url: env_var_opt("SUCCESS_WEBHOOK_URL"),
+        success_webhook_min_files: env_var_or("SUCCESS_WEBHOOK_MIN_FILES", 0),
+        success_webhook_min_bytes: env_var_or("SUCCESS_WEBHOOK_MIN_BYTES", 0),
+        strict_header: env_var_or("STRICT_HEADER", false),
+        ignore_header_quotes: env_var_or("IGNORE_HEADER_QUOTES", false),
+        rsync_progress_interval_seconds: env_var_or("RSYNC_PROGRESS_INTERVAL_SECONDS", 0),
+        remote_dir_mode: env_var_opt("REMOTE_DIR_MODE"),
+        rsync_retries: env_var_or("RSYNC_RETRIES", 3),
+        dead_letter_dir: env_var_opt("DEAD_LETTER_DIR"),
+        requeue_changed_files: env_var_or("REQUEUE_CHANGED_FILES", false),
+        follow_remounts: env_var_or("FOLLOW_REMOUNTS", false),
+        ordered_tables: env_list(&env_var_or("ORDERED_TABLES", String::new())),
+        check_dest_free_space: env_var_or("CHECK_DEST_FREE_SPACE", false),
+        check_dest_writable: env_var_or("CHECK_DEST_WRITABLE", false),
+        dest_free_space_margin_percent: env_var_or("DEST_FREE_SPACE_MARGIN_PERCENT", 10),
+        table_schema_files: env_list(&env_var_or("TABLE_SCHEMA_FILES", String::new()))
+            .iter()
+            .map(|mapping| match mapping.split_once('=') {
+                Some((table_name, schema_file)) => (table_name.trim().to_string(), schema_file.trim().to_string()),
+                None => panic!("Invalid TABLE_SCHEMA_FILES entry {:?}, expected TABLE=PATH", mapping),
+            })
+            .collect(),
+        schema_transfer: env_var_or("SCHEMA_TRANSFER", SchemaTransfer::Batch),
+        metrics_file: env_var_opt("METRICS_FILE"),
+        validate_utf8: env_var_or("VALIDATE_UTF8", false),
+        validate_utf8_bytes: env_var_or("VALIDATE_UTF8_BYTES", 64 * 1024),
+        invalid_file_quarantine_dir: env_var_opt("INVALID_FILE_QUARANTINE_DIR"),
+        symlink_metadata: env_var_or("SYMLINK_METADATA", SymlinkMetadata::Target),
+        include_globs: env_list(&env_var_or("INCLUDE_GLOBS", String::new())),
+        normalize_line_endings: env_var_or("NORMALIZE_LINE_ENDINGS", false),
+        batch_id_format: env_var_opt("BATCH_ID_FORMAT").map(|batch_id_format| {
+            batch_id_format
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid value for BATCH_ID_FORMAT: {}", e))
+        }),
+        source_max_age_hours: env_var_or("SOURCE_MAX_AGE_HOURS", 0),
+        source_purge_interval_minutes: env_var_or("SOURCE_PURGE_INTERVAL_MINUTES", 60),
+        source_purge_quarantine_dir: env_var_opt("SOURCE_PURGE_QUARANTINE_DIR"),
+        transfer_tool: env_var_or("TRANSFER_TOOL", TransferTool::Rsync),
+        status_file: env_var_opt("STATUS_FILE"),
+        rsync_delay_updates: env_var_or("RSYNC_DELAY_UPDATES", false),
+        hot_file_checks: env_var_or("HOT_FILE_CHECKS", 0),
+        rsync_bin: env_var_or("RSYNC_BIN", "rsync".to_string()),
+        rsync_times: env_var_or("RSYNC_TIMES", RsyncTimes::Preserve),
+        max_tables_per_cycle: env_var_or("MAX_TABLES_PER_CYCLE", 0),
+        dest_full_webhook_url: env_var_opt("DEST_FULL_WEBHOOK_URL"),
+        table_dest_owners: env_list(&env_var_or("TABLE_DEST_OWNER", String::new()))
+            .iter()
+            .map(|mapping| match mapping.split_once('=') {
+                Some((table_name, owner)) if !owner.trim().is_empty() => {
+                    (table_name.trim().to_string(), owner.trim().to_string())
+                }
+                _ => panic!("Invalid TABLE_DEST_OWNER entry {:?}, expected TABLE=OWNER[:GROUP]", mapping),
+            })
+            .collect(),
+        dest_chown_command: env_var_or("DEST_CHOWN_COMMAND", "sudo -n chown {owner} -- {files}".to_string()),
+        keep_local_metadata: env_var_or("KEEP_LOCAL_METADATA", false),
+        metadata_ledger_dir: env_var_opt("METADATA_LEDGER_DIR"),
+        tolerate_extra_trailing_columns: env_var_or("TOLERATE_EXTRA_TRAILING_COLUMNS", false),
+        upload_log_rotate_hours: env_var_or("UPLOAD_LOG_ROTATE_HOURS", 0),
+        upload_log_compress: env_var_or("UPLOAD_LOG_COMPRESS", true),
+        upload_log_archive_dir: env_var_opt("UPLOAD_LOG_ARCHIVE_DIR"),
+        event_merge_window_ms: env_var_or("EVENT_MERGE_WINDOW_MS", 0),
+        validate_types: env_var_or("VALIDATE_TYPES", false),
+        table_column_types: env_list(&env_var_or("TABLE_COLUMN_TYPES", String::new()))
+            .iter()
+            .map(|mapping| {
+                let invalid_entry = || {
+                    panic!("Invalid TABLE_COLUMN_TYPES entry {:?}, expected TABLE=COLUMN:TYPE[;COLUMN:TYPE...]", mapping)
+                };
+                let Some((table_name, column_types)) = mapping.split_once('=') else {
+                    invalid_entry()
+                };
+                let column_types = column_types
+                    .split(';')
+                    .map(|column_type| match column_type.split_once(':') {
+                        Some((column, column_type)) if !column.trim().is_empty() => {
+                            let column_type = column_type.trim().parse().unwrap_or_else(|e| {
+                                panic!("Invalid type in TABLE_COLUMN_TYPES entry {:?}: {}", mapping, e)
+                            });
+                            (column.trim().to_string(), column_type)
+                        }
+                        _ => invalid_entry(),
+                    })
+                    .collect();
+                (table_name.trim().to_string(), column_types)
+            })
+            .collect(),
+        types_allow_empty: env_var_or("TYPES_ALLOW_EMPTY", true),
+        rsync_rsh: env_var_opt("RSYNC_RSH"),
+        transfer_pipeline_depth: env_var_or("TRANSFER_PIPELINE_DEPTH", 0),
+        dir_quiet_seconds: env_var_or("DIR_QUIET_SECONDS", 0),
+        hot_file_webhook_url: env_var_opt("HOT_FILE_WEBHOOK_URL"),
+        run_as_uid: env_var_opt("RUN_AS_USER").map(|user| {
+            resolve_id(&["-u"], &user).unwrap_or_else(|e| panic!("Invalid RUN_AS_USER {:?}: {}", user, e))
+        }),
+        // Without RUN_AS_GROUP, the primary group of RUN_AS_USER is used so the root group is dropped too
+        run_as_gid: match (env_var_opt("RUN_AS_GROUP"), env_var_opt("RUN_AS_USER")) {
+            (Some(group), _) => {
+                Some(resolve_group(&group).unwrap_or_else(|e| panic!("Invalid RUN_AS_GROUP {:?}: {}", group, e)))
+            }
+            (None, Some(user)) => {
+                Some(resolve_id(&["-g"], &user).unwrap_or_else(|e| panic!("Invalid RUN_AS_USER {:?}: {}", user, e)))
+            }
+            (None, None) => None,
+        },
+        metadata_date_columns: env_list(&env_var_or("METADATA_DATE_COLUMNS", String::new()))
+            .iter()
+            .map(|mapping| match mapping.split_once('=') {
+                Some((table_name, column)) => (table_name.trim().to_string(), column.trim().to_string()),
+                None => panic!("Invalid METADATA_DATE_COLUMNS entry {:?}, expected TABLE=COLUMN", mapping),
+            })
+            .collect(),
+    };
+    for time_format in [&config.metadata_time_format, &config.file_suffix] {
+        match validate_time_format(time_format) {
+            Ok(sample) => info!("Time format {:?} renders as {:?}", time_format, sample),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    if let Some(proxy_command) = &config.ssh_proxy_command {
+        let proxy_program = proxy_command.split_whitespace().next().unwrap_or_default();
+        match find_executable(proxy_program) {
+            Some(path) => info!("Using SSH proxy command via {}", path.display()),
+            None => panic!("SSH_PROXY_COMMAND program {:?} not found or not executable", proxy_program),
+        }
+    }
+    if config.transfer_tool == TransferTool::Rsync {
+        match find_executable(&config.rsync_bin) {
+            Some(path) => info!("Using rsync at {}", path.display()),
+            // Without RSYNC_BIN, a missing rsync only fails the transfers, e.g. for subcommands run elsewhere
+            None if env_var_opt("RSYNC_BIN").is_none() => warn!("rsync not found in PATH, transfers will fail"),
+            None => panic!("RSYNC_BIN {:?} not found or not executable", config.rsync_bin),
+        }
+    }
+    if let Some(rsync_rsh) = &config.rsync_rsh {
+        let Some(program) = rsync_rsh.split_whitespace().next() else {
+            panic!("RSYNC_RSH must not be empty");
+        };
+        if find_executable(program).is_none() {
+            panic!("RSYNC_RSH program {:?} not found or not executable", program);
+        }
+        // SSH_PROXY_COMMAND and SSH_CONTROL_PATH are ssh options, which a custom remote shell doesn't take
+        if !is_ssh_command(rsync_rsh) && !ssh_options(&config).is_empty() {
+            warn!("RSYNC_RSH {:?} is not ssh, ssh options are not passed to it", rsync_rsh);
+        }
+    }
+    if let Some(chmod) = &config.rsync_chmod {
+        if chmod.split(',').any(|item| item.trim().is_empty()) {
+            panic!("Invalid RSYNC_CHMOD {:?}: empty chmod item", chmod);
+        }
+    }
+    if let Some(mode) = &config.remote_dir_mode {
+        // Only octal modes are accepted, the mode is placed unquoted in the remote command
+        if mode.is_empty() || mode.len() > 4 || !mode.chars().all(|c| ('0'..='7').contains(&c)) {
+            panic!("Invalid REMOTE_DIR_MODE {:?}: expected an octal mode such as 0755", mode);
+        }
+    }
+    if config.template_dirs.is_empty() && config.template_manifest.is_none() {
+        panic!("TEMPLATE_DIR or TEMPLATE_MANIFEST must be set");
+    }
+    if !config.table_dest_owners.is_empty() && !config.dest_chown_command.contains("{files}") {
+        panic!("DEST_CHOWN_COMMAND {:?} must contain {{files}}", config.dest_chown_command);
+    }
+    for (table_name, schema_file) in &config.table_schema_files {
+        if !Path::new(schema_file).is_file() {
+            panic!("TABLE_SCHEMA_FILES file {:?} of table {} not found", schema_file, table_name);
+        }
+    }
+    if !(0.0..=1.0).contains(&config.header_drift_threshold) {
+        panic!("HEADER_DRIFT_THRESHOLD must be between 0 and 1, got {}", config.header_drift_threshold);
+    }
+    if let Some(dest_file_name) = &config.dest_file_name {
+        // Files are renamed by the remote move that publishes them
+        if !config.atomic_publish {
+            panic!("DEST_FILE_NAME requires ATOMIC_PUBLISH");
+        }
+        if dest_file_name.contains('/') {
+            panic!("Invalid DEST_FILE_NAME {:?}: must be a file name, not a path", dest_file_name);
+        }
+    }
+    if config.transfer_tool == TransferTool::Scp {
+        // These rsync features have no scp equivalent
+        let rsync_only_options = [
+            ("RSYNC_CHMOD", config.rsync_chmod.is_some()),
+            ("RSYNC_PASSWORD_FILE", config.rsync_password_file.is_some()),
+            ("RSYNC_PASSWORD_ENV", config.rsync_password_env.is_some()),
+            ("RSYNC_DELAY_UPDATES", config.rsync_delay_updates),
+            ("RSYNC_RSH", config.rsync_rsh.is_some()),
+        ];
+        if let Some((option, _)) = rsync_only_options.iter().find(|(_, is_set)| *is_set) {
+            panic!("{} requires TRANSFER_TOOL=rsync", option);
+        }
+    }
+    if config.rollup_manifest && !config.batch_manifest {
+        panic!("ROLLUP_MANIFEST requires BATCH_MANIFEST");
+    }
+    if config.empty_table_action == EmptyTableAction::Quarantine && config.empty_table_quarantine_dir.is_none() {
+        panic!("EMPTY_TABLE_ACTION=quarantine requires EMPTY_TABLE_QUARANTINE_DIR");
+    }
+    if let Some(password_file) = &config.rsync_password_file {
+        // rsync refuses password files readable by other users
+        match fs::metadata(password_file) {
+            Ok(attr) if attr.mode() & 0o077 != 0 => warn!(
+                "RSYNC_PASSWORD_FILE {:?} is accessible by other users, rsync will reject it",
+                password_file
+            ),
+            Ok(_) => (),
+            Err(e) => panic!("RSYNC_PASSWORD_FILE {:?} is not readable: {}", password_file, e),
+        }
+    }
+    if config.trigger_on == TriggerOn::Create && config.min_file_age_seconds == 0 {
+        // Without modify events nothing else tells that a newly created file is still being written
+        config.min_file_age_seconds = config.csv_event_wait_seconds.max(1);
+        info!(
+            "TRIGGER_ON=create without MIN_FILE_AGE_SECONDS, waiting for files to be unchanged for {} seconds",
+            config.min_file_age_seconds
+        );
+    }
+    if let Some(control_path) = &config.ssh_control_path {
+        // Unix socket paths are limited to 108 bytes and ssh appends a random suffix while connecting
+        if control_path.len() > 80 && !control_path.contains("%C") {
+            warn!(
+                "SSH_CONTROL_PATH {:?} may exceed the unix socket path length limit. Consider a short path using %C.",
+                control_path
+            );
+        }
+    }
+    config
+}
+
+fn env_list(value: &str) -> Vec<String> {
+    // Split comma separated environment variable value into its non-empty items
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn load_dotenv() {
+    // Load the .env file without overriding variables already set in the environment.
+    // dotenv expands variables in the file itself and replaces unresolved ones with an empty string, so the file
+    // is checked for unresolved variables here and its values are not expanded again by env_value.
+    let process_keys: HashSet<String> = env::vars_os().filter_map(|(key, _)| key.into_string().ok()).collect();
+    let Ok(dotenv_path) = dotenv() else {
+        return;
+    };
+    let mut file_keys: HashSet<String> = HashSet::new();
+    for line in fs::read_to_string(&dotenv_path).unwrap_or_default().lines() {
+        let line = line.trim();
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=').filter(|_| !line.starts_with('#')) else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        // Single quoted values are taken literally
+        if !value.starts_with('\'') {
+            // The .env loader has no $$ escape, it would expand the variable that follows
+            if value.contains("$$") {
+                panic!(
+                    "Invalid value for {} in {}: use \\$ for a literal $ in the .env file",
+                    key,
+                    dotenv_path.display()
+                );
+            }
+            let is_defined = |name: &str| (process_keys.contains(name) || file_keys.contains(name)).then(String::new);
+            if let Err(e) = expand_env_vars(value.trim_matches('"'), is_defined) {
+                panic!("Invalid value for {} in {}: {}", key, dotenv_path.display(), e);
+            }
+        }
+        if !process_keys.contains(key) {
+            file_keys.insert(key.to_string());
+        }
+    }
+    *DOTENV_KEYS.lock().unwrap() = Some(file_keys);
+}
+
+fn expand_env_vars(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    // Expand $NAME and ${NAME} references with lookup, failing on unresolved variables.
+    // \$ and $$ are a literal $, as is a $ not followed by a variable name.
+    let mut expanded = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&'$') {
+            expanded.push(chars.next().unwrap());
+            continue;
+        }
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+        if chars.next_if_eq(&'$').is_some() {
+            expanded.push('$');
+            continue;
+        }
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        if braced && (name.is_empty() || chars.next_if_eq(&'}').is_none()) {
+            return Err(format!("invalid variable reference ${{{}", name));
+        }
+        if name.is_empty() {
+            expanded.push('$');
+            continue;
+        }
+        match lookup(&name) {
+            Some(name_value) => expanded.push_str(&name_value),
+            None => return Err(format!("unresolved variable ${} (escape a literal $ as \\$)", name)),
+        }
+    }
+    Ok(expanded)
+}
+
+fn env_value(key: &str) -> Option<String> {
+    // Read environment variable, expanding variable references in values not loaded from the .env file
+    let value = env::var(key).ok()?;
+    if DOTENV_KEYS.lock().unwrap().as_ref().is_some_and(|file_keys| file_keys.contains(key)) {
+        return Some(value);
+    }
+    let expanded = expand_env_vars(&value, |name| env::var(name).ok())
+        .unwrap_or_else(|e| panic!("Invalid value for {}: {}", key, e));
+    Some(expanded)
+}
+
+fn env_var_opt(key: &str) -> Option<String> {
+    // Read optional environment variable, returning None if unset or empty
+    match env_value(key) {
+        Some(value) if !value.trim().is_empty() => Some(value.trim().to_string()),
+        _ => None,
+    }
+}
+
+fn env_var_or<T: std::str::FromStr>(key: &str, default: T) -> T
+where
+    T::Err: std::fmt::Debug,
+{
+    // Read optional environment variable, falling back to default if unset or empty
+    match env_value(key) {
+        Some(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse::<T>()
+            .unwrap_or_else(|e| panic!("Invalid value for {}: {:?}", key, e)),
+        _ => default,
+    }
+}
+
+fn load_headers(
+    template_dirs: &[String],
+    template_manifest: Option<&str>,
+    ignore_quotes: bool,
+) -> std::io::Result<TemplateMap> {
+    // Load headers from template csv files of all template directories, then from the template manifest,
+    // and store in hashmap keyed by header hash. With ignore_quotes, quoted columns are stored unquoted.
+    // A table in a later source overrides the template of the same table loaded from an earlier one.
+    let mut table_headers: TemplateMap = HashMap::new();
+    let mut table_sources: HashMap<String, String> = HashMap::new();
+    for template_dir in template_dirs {
+        let template_files = std::fs::read_dir(template_dir).unwrap();
+        for template_file in template_files {
+            let template_path = template_file?.path();
+            match template_path.clone().file_stem() {
+                Some(fname) => match &fname.to_str() {
+                    Some(v) => {
+                        let table_name = v.strip_suffix("_template").unwrap().to_string();
+                        let mut file = File::open(template_path).unwrap();
+                        let mut headers = String::new();
+                        let _ = file.read_to_string(&mut headers);
+                        if ignore_quotes {
+                            headers = unquote_header(&headers);
+                        }
+                        add_template(&mut table_headers, &mut table_sources, table_name, &headers, template_dir);
+                    }
+                    None => info!("Invalid File Name"),
+                },
+                None => error!("No File Name"),
+            }
+        }
+    }
+    if let Some(template_manifest) = template_manifest {
+        for (table_name, mut headers) in read_template_manifest(template_manifest)? {
+            if ignore_quotes {
+                headers = unquote_header(&headers);
+            }
+            add_template(&mut table_headers, &mut table_sources, table_name, &headers, template_manifest);
+        }
+    }
+    Ok(table_headers)
+}
+
+fn add_template(
+    table_headers: &mut TemplateMap,
+    table_sources: &mut HashMap<String, String>,
+    table_name: String,
+    headers: &str,
+    source: &str,
+) {
+    // Add a table template, replacing the template of the same table loaded from another source
+    let headers = normalize_header(headers).to_string();
+    match table_sources.insert(table_name.clone(), source.to_string()) {
+        Some(previous_source) if previous_source != source => {
+            info!(
+                "Template of table {} in {} overrides the one in {}",
+                table_name, source, previous_source
+            );
+            for bucket in table_headers.values_mut() {
+                bucket.retain(|template| template.table_name != table_name);
+            }
+            table_headers.retain(|_, bucket| !bucket.is_empty());
+        }
+        _ => (),
+    }
+    let bucket = table_headers.entry(header_hash(&headers)).or_default();
+    // Templates with identical headers resolve to the last one loaded
+    bucket.retain(|template| template.headers != headers);
+    bucket.push(Template { headers, table_name });
+}
+
+fn read_template_manifest(template_manifest: &str) -> std::io::Result<Vec<(String, String)>> {
+    // Read table names and header lines from a template manifest. A .json manifest is an object mapping
+    // each table name to its header line, otherwise each line is "table_name,header_line".
+    // Empty lines and lines starting with '#' are ignored.
+    let manifest = fs::read_to_string(template_manifest)?;
+    let invalid_data = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    if Path::new(template_manifest).extension().and_then(|s| s.to_str()) == Some("json") {
+        let tables: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&manifest)
+            .map_err(|e| invalid_data(format!("Invalid template manifest {}: {}", template_manifest, e)))?;
+        return tables
+            .into_iter()
+            .map(|(table_name, headers)| match headers {
+                serde_json::Value::String(headers) => Ok((table_name, headers)),
+                _ => Err(invalid_data(format!(
+                    "Invalid template manifest {}: header of table {} is not a string",
+                    template_manifest, table_name
+                ))),
+            })
+            .collect();
+    }
+    manifest
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|line| match line.split_once(',') {
+            Some((table_name, headers)) => Ok((table_name.trim().to_string(), headers.to_string())),
+            None => Err(invalid_data(format!(
+                "Invalid template manifest {} line {:?}, expected table_name,header_line",
+                template_manifest, line
+            ))),
+        })
+        .collect()
+}
+
+fn suffix_file_name(src_file: &str, config: &Config) -> std::io::Result<String> {
+    // Rename source file by suffixiing source file with timestamp
+    let binding = PathBuf::from(src_file);
+    let mut src_file_basename_no_ext = binding.file_stem().unwrap().to_string_lossy().to_string();
+    // Truncate if file name exceeds 218 chars. Linux default file name char limit is 255, path char limit is 4096.
+    let max_file_len = 218;
+    if src_file_basename_no_ext.len() > max_file_len {
+        info!("File name too long. Truncating file stem from {:?} to {:?}", src_file_basename_no_ext, &src_file_basename_no_ext[..max_file_len]);
+        src_file_basename_no_ext.truncate(max_file_len);
+    }
+    let src_file_suffix =
+        format_timestamp(chrono::Utc::now(), &config.file_suffix, config.metadata_timezone);
+    // Files picked up by content sniffing have no extension to preserve.
+    // If a file with the suffixed name is still waiting for its transfer (e.g. same base name within the
+    // resolution of FILE_SUFFIX), a counter is appended rather than overwriting it. The suffixed name is
+    // claimed with a hard link, which fails if the name exists, so a file created meanwhile is never replaced.
+    // Filesystems without hard links fall back to checking the name, then renaming.
+    let mut collision_count = 0;
+    let mut renamed = false;
+    let src_file_with_suffix = loop {
+        let src_file_suffix = match collision_count {
+            0 => src_file_suffix.clone(),
+            count => format!("{}_{}", src_file_suffix, count),
+        };
+        let src_file_with_suffix = binding.with_file_name(match binding.extension() {
+            Some(src_file_extension) => format!(
+                "{}_{}.{}",
+                src_file_basename_no_ext,
+                src_file_suffix,
+                src_file_extension.to_string_lossy()
+            ),
+            None => format!("{}_{}", src_file_basename_no_ext, src_file_suffix),
+        });
+        match fs::hard_link(src_file, &src_file_with_suffix) {
+            Ok(()) => {
+                SUFFIX_LINKS.lock().unwrap().insert(src_file_with_suffix.clone());
+                break src_file_with_suffix;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => collision_count += 1,
+            // Some filesystems (e.g. FUSE or SMB mounts) report hard links as unsupported, Linux reports EPERM
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::Unsupported | std::io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                if src_file_with_suffix.exists() {
+                    collision_count += 1;
+                    continue;
+                }
+                debug!("Hard link to {:?} failed ({}), renaming instead", src_file_with_suffix, err);
+                if let Err(err) = fs::rename(src_file, &src_file_with_suffix) {
+                    error!("Failed to rename source file. Error: {}", err);
+                    return Err(err);
+                }
+                renamed = true;
+                break src_file_with_suffix;
+            }
+            Err(err) => {
+                error!("Failed to rename source file. Error: {}", err);
+                return Err(err);
+            }
+        }
+    };
+    if collision_count > 0 {
+        warn!(
+            "Suffixed name of {:?} already exists, using {:?} instead",
+            src_file, src_file_with_suffix
+        );
+    }
+    if !renamed {
+        if let Err(err) = fs::remove_file(src_file) {
+            error!("Failed to rename source file. Error: {}", err);
+            remove_file_if_exists(src_file_with_suffix.to_str().unwrap());
+            return Err(err);
+        }
+    }
+    Ok(src_file_with_suffix.to_str().unwrap().to_string())
+}
+
+fn prepare_upload_file(src_file: &str, table_name: &str, config: &Config) -> Result<Option<String>, String> {
+    // Create the temporary copy uploaded in place of src_file, if the table has column renames or the file has
+    // CRLF line endings with NORMALIZE_LINE_ENDINGS. Returns None if the source file is uploaded as is.
+    let mut upload_file = None;
+    if config.column_rename_map.iter().any(|(table, _, _)| table == table_name) {
+        match rename_columns(src_file, table_name, config) {
+            Ok(file) => upload_file = Some(file),
+            Err(e) => {
+                error!("Error renaming columns of {:?}: {:?}", src_file, e);
+                return Err(format!("Failed to rename columns: {}", e));
+            }
+        }
+    }
+    if config.normalize_line_endings {
+        let copied_file = upload_file.as_deref().unwrap_or(src_file);
+        match normalize_line_endings(copied_file) {
+            Ok(Some(normalized_file)) => {
+                // The renamed copy is replaced by its normalized copy
+                if let Some(renamed_file) = upload_file.replace(normalized_file) {
+                    remove_file_if_exists(&renamed_file);
+                    if let Some(rename_dir) = Path::new(&renamed_file).parent() {
+                        let _ = fs::remove_dir(rename_dir);
+                    }
                 }
             }
+            Ok(None) => (),
+            Err(e) => {
+                error!("Error normalizing line endings of {:?}: {:?}", copied_file, e);
+                return Err(format!("Failed to normalize line endings: {}", e));
+            }
         }
     }
-    // If csv file does not exist or no matching table headers, return empty string
-    Ok(String::new())
+    Ok(upload_file)
 }
 
-fn delete_src_file_and_metadata(src_file: &str, src_file_metadata: &str) {
-    // Delete source file and metadata after rsync
-    let files_to_remove = vec![src_file, src_file_metadata];
-    info!(
-        "Attempting to delete source file and metadata: {}, {}",
-        src_file, src_file_metadata
-    );
-    for file in files_to_remove {
-        match fs::remove_file(file) {
-            Ok(_) => info!("Successfully removed {}", file),
-            Err(e) => error!("Failed to remove {}: {}", file, e),
+fn normalize_line_endings(file_path: &str) -> std::io::Result<Option<String>> {
+    // Copy a file with CRLF line endings to a temporary directory with LF line endings, keeping its name.
+    // Files without any CRLF are not copied and None is returned. Lone CR characters are kept.
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut line: Vec<u8> = Vec::new();
+    let mut has_crlf = false;
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if line.ends_with(b"\r\n") {
+            has_crlf = true;
+            break;
+        }
+        line.clear();
+    }
+    if !has_crlf {
+        return Ok(None);
+    }
+    static NORMALIZE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let normalize_dir = env::temp_dir().join(format!(
+        "rsync_csv_{}_lf_{}",
+        std::process::id(),
+        NORMALIZE_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&normalize_dir)?;
+    let normalized_file_path = normalize_dir.join(Path::new(file_path).file_name().unwrap());
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut writer = std::io::BufWriter::new(File::create(&normalized_file_path)?);
+    line.clear();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if line.ends_with(b"\r\n") {
+            line.truncate(line.len() - 2);
+            line.push(b'\n');
         }
+        writer.write_all(&line)?;
+        line.clear();
     }
+    writer.flush()?;
+    info!("Normalized line endings of {:?} into {:?}", file_path, normalized_file_path);
+    Ok(Some(normalized_file_path.to_str().unwrap().to_string()))
 }
 
-fn log_upload_status(log_dir: &str, log_msg: String) {
-    // Create an upload log file at specified log directory
-    let log_file_path = Path::new(log_dir).join("upload.log");
-    let log_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-    match fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(log_file_path)
-    {
-        Ok(mut log_file) => match log_file.write(format!("{log_time} - {log_msg}\n").as_bytes()) {
-            Ok(_) => info!("Upload log file updated successfully."),
-            Err(e) => error!("Failed to write to upload log file. Error: {}", e),
-        },
-        Err(e) => error!("Failed to create upload log file. Error: {}", e),
+fn rename_columns(src_file: &str, table_name: &str, config: &Config) -> std::io::Result<String> {
+    // Copy src_file to a temporary directory with the table's COLUMN_RENAME_MAP applied to its header line.
+    // Data rows are copied unchanged. The copy keeps the file name, so it lands at the same remote path.
+    static RENAME_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let rename_dir = env::temp_dir().join(format!(
+        "rsync_csv_{}_rename_{}",
+        std::process::id(),
+        RENAME_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&rename_dir)?;
+    let upload_file_path = rename_dir.join(Path::new(src_file).file_name().unwrap());
+    let mut reader = BufReader::new(File::open(src_file)?);
+    let mut writer = std::io::BufWriter::new(File::create(&upload_file_path)?);
+    let mut line: Vec<u8> = Vec::new();
+    for _ in 0..config.header_row_index {
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        writer.write_all(&line)?;
     }
+    line.clear();
+    reader.read_until(b'\n', &mut line)?;
+    let header_line = String::from_utf8_lossy(&line).to_string();
+    let header = header_line.trim_end_matches(['\r', '\n']);
+    // Columns are matched by their unquoted name and written back quoted only where needed, so quoted columns
+    // containing commas keep their place
+    let renamed_header: Vec<String> = split_csv_fields(header)
+        .iter()
+        .map(|column| {
+            let new_name = config
+                .column_rename_map
+                .iter()
+                .find(|(table, old_name, _)| table == table_name && old_name == column.trim())
+                .map_or(column.as_str(), |(_, _, new_name)| new_name.as_str());
+            csv_field(new_name)
+        })
+        .collect();
+    // Keep the original line ending
+    writer.write_all(renamed_header.join(",").as_bytes())?;
+    writer.write_all(&header_line.as_bytes()[header.len()..])?;
+    std::io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    info!("Renamed columns of {:?} into {:?}", src_file, upload_file_path);
+    Ok(upload_file_path.to_str().unwrap().to_string())
 }
 
-fn run_rsync(
-    rsync_hashmap: &HashMap<String, HashMap<String, Vec<String>>>,
-    dest_user: &str,
-    dest_host: &str,
-    dest_dir: &str,
-    retry_count: u8,
-) -> Result<(), String> {
-    // Run rsync command to sync csv files to destination host
-    debug!("Rsync Hashmap: {:?}", rsync_hashmap);
-    for table_name in rsync_hashmap.keys() {
-        let table_entry = rsync_hashmap.get(table_name).unwrap();
-        let src_files = table_entry.get("src_files").unwrap();
-        let metadata_files = table_entry.get("metadata_files").unwrap();
-        let mkdir_command = format!(
-            "\"mkdir -p \"{}\" && rsync\"",
-            PathBuf::from(dest_dir).join(table_name).display()
-        );
-        let rsync_command = format!(
-            "rsync -aLvz --partial-dir=tmp --timeout=10 --rsync-path={} \"{}\" \"{}\" {}@{}:{}",
-            mkdir_command,
-            src_files.join("\" \""),
-            metadata_files.join("\" \""),
-            dest_user,
-            dest_host,
-            PathBuf::from(dest_dir).join(table_name).display()
-        );
-        info!("Running rsync command: {}", rsync_command);
-        match Command::new("sh").arg("-c").arg(&rsync_command).output() {
-            Ok(output) => {
-                if output.status.success() {
-                    info!("Success: {}", String::from_utf8_lossy(&output.stdout));
-                    for src_file in src_files {
-                        let src_file_metadata =
-                            &metadata_files[src_files.iter().position(|x| x == src_file).unwrap()];
-                        let binding = PathBuf::from(src_file);
-                        let src_file_basename = binding.file_name().unwrap().to_str().unwrap();
-                        delete_src_file_and_metadata(src_file, src_file_metadata);
-                        match PathBuf::from(src_file).parent() {
-                            Some(log_dir) => log_upload_status(
-                                log_dir.to_str().unwrap(),
-                                format!("Upload succeeded! File: {src_file_basename}").to_string(),
-                            ),
-                            None => {
-                                error!("Failed to get source file parent directory");
-                                Err("Failed to get source file parent directory")?;
-                            }
-                        }
-                    }
-                } else {
-                    let err_msg = String::from_utf8_lossy(&output.stderr);
-                    error!("Error: {}", err_msg);
-                    if retry_count < 3 {
-                        info!("Retrying rsync command...");
-                        let _ = run_rsync(
-                            &rsync_hashmap,
-                            &dest_user,
-                            &dest_host,
-                            &dest_dir,
-                            retry_count + 1,
-                        );
-                    } else {
-                        for src_file in src_files {
-                            let binding = PathBuf::from(src_file);
-                            let src_file_basename = binding.file_name().unwrap().to_str().unwrap();
-                            match PathBuf::from(src_file).parent() {
-                                Some(log_dir) => log_upload_status(
-                                    log_dir.to_str().unwrap(),
-                                    format!(
-                                        "Upload failed! File: {src_file_basename} Reason: {err_msg}"
-                                    )
-                                    .to_string(),
-                                ),
-                                None => {
-                                    error!("Failed to get source file parent directory");
-                                    Err("Failed to get source file parent directory")?;
-                                }
-                            }
-                        }
-                    }
-                }
-            },
-            Err(e) => {
-                error!("Failed to execute rsync command. Error: {}", e);
-                Err("Failed to get source file parent directory")?;
-            }
-        }
+fn create_md5_sidecar(src_file: &str, upload_file: &str) -> std::io::Result<String> {
+    // Create <file>.md5 in md5sum format for the uploaded content of src_file (a renamed copy has the same name).
+    // md5sum streams the file so large files are not read into memory.
+    let binding = PathBuf::from(upload_file);
+    let src_file_basename = binding.file_name().unwrap();
+    let output = Command::new("md5sum")
+        .arg(src_file_basename)
+        .current_dir(binding.parent().unwrap_or(Path::new(".")))
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "md5sum failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
     }
-    Ok(())
+    let md5_file_path = format!("{}.md5", src_file);
+    fs::write(&md5_file_path, &output.stdout)?;
+    info!("MD5 sidecar file {:?} created successfully.", md5_file_path);
+    Ok(md5_file_path)
 }
 
-fn load_env_vars() -> (String, String, String, String, String, String, u64, u64) {
-    // Load environment variables and set rsync src and dest paths
-    dotenv().ok();
-    let src_dir = env::var("SOURCE_DIR").unwrap();
-    let dest_user = env::var("DEST_USER").unwrap();
-    let dest_host = env::var("DEST_HOST").unwrap();
-    let dest_dir = env::var("DEST_DIR").unwrap();
-    let template_dir = env::var("TEMPLATE_DIR").unwrap();
-    let file_suffix = env::var("FILE_SUFFIX").unwrap();
-    let csv_event_wait_seconds = env::var("CSV_EVENT_WAIT_SECONDS")
-        .unwrap()
-        .parse::<u64>()
-        .unwrap();
-    let csv_event_upper_limit = env::var("CSV_EVENT_UPPER_LIMIT")
-        .unwrap()
-        .parse::<u64>()
-        .unwrap();
-    (
-        src_dir,
-        dest_user,
-        dest_host,
-        dest_dir,
-        template_dir,
-        file_suffix,
-        csv_event_wait_seconds,
-        csv_event_upper_limit,
-    )
+fn format_timestamp(
+    time: chrono::DateTime<chrono::Utc>,
+    time_format: &str,
+    timezone: TimestampZone,
+) -> String {
+    // Format timestamp in the configured timezone
+    match timezone {
+        TimestampZone::Local => time.with_timezone(&chrono::Local).format(time_format).to_string(),
+        TimestampZone::Utc => time.format(time_format).to_string(),
+    }
 }
 
-fn load_headers(template_dir: String) -> std::io::Result<HashMap<String, String>> {
-    // Load headers from template csv files and store in hashmap
-    let mut table_headers: HashMap<String, String> = HashMap::new();
-    let template_files = std::fs::read_dir(template_dir).unwrap();
-    for template_file in template_files {
-        let template_path = template_file?.path();
-        match template_path.clone().file_stem() {
-            Some(fname) => match &fname.to_str() {
-                Some(v) => {
-                    let table_name = v.strip_suffix("_template").unwrap().to_string();
-                    let mut file = File::open(template_path).unwrap();
-                    let mut headers = String::new();
-                    let _ = file.read_to_string(&mut headers);
-                    headers = headers.trim().to_string();
-                    table_headers.insert(headers, table_name);
-                }
-                None => info!("Invalid File Name"),
-            },
-            None => error!("No File Name"),
-        }
+fn zone_now(timezone: TimestampZone) -> chrono::NaiveDateTime {
+    // Current wall clock time in METADATA_TIMEZONE
+    match timezone {
+        TimestampZone::Local => chrono::Local::now().naive_local(),
+        TimestampZone::Utc => chrono::Utc::now().naive_utc(),
     }
-    Ok(table_headers)
 }
 
-fn suffix_file_name(src_file: &str, file_suffix: &str) -> std::io::Result<String> {
-    // Rename source file by suffixiing source file with timestamp
-    let binding = PathBuf::from(src_file);
-    let mut src_file_basename_no_ext = binding.file_stem().unwrap().to_string_lossy().to_string();
-    // Truncate if file name exceeds 218 chars. Linux default file name char limit is 255, path char limit is 4096.
-    let max_file_len = 218;
-    if src_file_basename_no_ext.len() > max_file_len {
-        info!("File name too long. Truncating file stem from {:?} to {:?}", src_file_basename_no_ext, &src_file_basename_no_ext[..max_file_len]);
-        src_file_basename_no_ext.truncate(max_file_len);
+fn validate_time_format(time_format: &str) -> Result<String, String> {
+    // Render a sample timestamp so invalid format strings are caught at startup rather than mid-batch
+    use std::fmt::Write as _;
+    let mut sample = String::new();
+    match write!(sample, "{}", chrono::Utc::now().format(time_format)) {
+        Ok(_) => Ok(sample),
+        Err(_) => Err(format!("Invalid time format: {:?}", time_format)),
     }
-    let src_file_extension = binding.extension().unwrap().to_string_lossy().to_string();
-    let src_file_suffix = chrono::Local::now().format(file_suffix).to_string();
-    let src_file_with_suffix = format!(
-        "{}_{}.{}",
-        src_file_basename_no_ext, src_file_suffix, src_file_extension
-    );
-    let src_file_with_suffix = binding.with_file_name(src_file_with_suffix);
-    if let Err(err) = fs::rename(src_file, &src_file_with_suffix) {
-        error!("Failed to rename source file. Error: {}", err);
-        return Err(err)?;
+}
+
+fn source_file_metadata(file_path: &str, config: &Config) -> std::io::Result<fs::Metadata> {
+    // Attributes recorded as the file's owner and creation time. For a symlinked source file these are
+    // the ones of the file it points to, or of the link itself with SYMLINK_METADATA=link.
+    match config.symlink_metadata {
+        SymlinkMetadata::Target => fs::metadata(file_path),
+        SymlinkMetadata::Link => fs::symlink_metadata(file_path),
     }
-    Ok(src_file_with_suffix.to_str().unwrap().to_string())
 }
 
-fn get_file_owner(file_path: &str) -> std::io::Result<String> {
-    let attr = fs::metadata(file_path)?;
+fn get_file_owner(file_path: &str, config: &Config) -> std::io::Result<String> {
+    let attr = source_file_metadata(file_path, config)?;
     let mut username: String = "".to_string();
     match Command::new("id")
         .arg("-u")
@@ -438,23 +5554,52 @@ fn get_file_owner(file_path: &str) -> std::io::Result<String> {
     Ok(username)
 }
 
-fn create_metadata_file(src_file: &str) -> std::io::Result<String> {
+fn local_hostname() -> String {
+    // Name of the local host, used to record which machine produced a file
+    match Command::new("hostname").output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        _ => fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|hostname| hostname.trim().to_string())
+            .unwrap_or_else(|e| panic!("Failed to determine local hostname: {}", e)),
+    }
+}
+
+fn create_metadata_file(src_file: &str, table_name: &str, config: &Config) -> std::io::Result<String> {
     // Create metadata file
-    let username = get_file_owner(src_file).unwrap();
-    let attr = fs::metadata(src_file)?;
+    let username = get_file_owner(src_file, config).unwrap();
+    let attr = source_file_metadata(src_file, config)?;
     let elapsed_secs = attr
         .created()?
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_secs() as i64;
-    let upload_time = chrono::Local
-        .timestamp_opt(elapsed_secs, 0)
-        .unwrap()
-        .format("%Y-%m-%d %H:%M:%S")
-        .to_string();
+    let upload_time = format_timestamp(
+        chrono::Utc.timestamp_opt(elapsed_secs, 0).unwrap(),
+        &config.metadata_time_format,
+        config.metadata_timezone,
+    );
     let binding = PathBuf::from(src_file);
     let src_file_basename = binding.file_name().unwrap().to_string_lossy().to_string();
-    let metadata_data = format!("{},{},{}\n", upload_time, username, src_file_basename);
+    let mut metadata_fields = vec![upload_time, username, src_file_basename];
+    if let Some(hostname) = &config.source_hostname {
+        metadata_fields.push(hostname.clone());
+    }
+    if let Some((_, date_column)) = config.metadata_date_columns.iter().find(|(table, _)| table == table_name) {
+        let (min_date, max_date, unparseable_rows) = date_column_range(src_file, date_column, config)?;
+        if unparseable_rows > 0 {
+            warn!(
+                "{} row(s) of {} have no parseable date in column {}",
+                unparseable_rows, src_file, date_column
+            );
+        }
+        metadata_fields.push(min_date.unwrap_or_default());
+        metadata_fields.push(max_date.unwrap_or_default());
+        metadata_fields.push(unparseable_rows.to_string());
+    }
+    if let Some(batch_id) = current_batch_id() {
+        metadata_fields.push(batch_id);
+    }
+    let metadata_data = format!("{}\n", metadata_fields.join(","));
     let metadata_file_path = format!("{}.metadata", src_file);
     info!(
         "Creating metadata file {:?} with metadata: {:?}",
@@ -464,7 +5609,7 @@ fn create_metadata_file(src_file: &str) -> std::io::Result<String> {
         Ok(file) => file,
         Err(err) => {
             error!("Failed to create metadata file: {:?}", err);
-            return Err(err)?;
+            return Err(err);
         }
     };
     metadata_file.write_all(metadata_data.as_bytes())?;
@@ -472,28 +5617,852 @@ fn create_metadata_file(src_file: &str) -> std::io::Result<String> {
     Ok(metadata_file_path)
 }
 
-fn main() -> std::io::Result<()> {
-    SimpleLogger::new().init().unwrap();
-    let (
-        src_dir,
-        dest_user,
-        dest_host,
-        dest_dir,
-        template_dir,
-        file_suffix,
-        csv_event_wait_seconds,
-        csv_event_upper_limit,
-    ) = load_env_vars();
-    let hashmap = load_headers(template_dir)?;
-    let _ = watch_for_file_changes(
-        src_dir,
-        dest_user,
-        dest_host,
-        dest_dir,
-        hashmap,
-        file_suffix,
-        csv_event_wait_seconds,
-        csv_event_upper_limit,
+fn date_column_range(
+    file_path: &str,
+    date_column: &str,
+    config: &Config,
+) -> std::io::Result<(Option<String>, Option<String>, u64)> {
+    // Stream the data rows of a csv file and return the earliest and latest value of a date or
+    // date time column, as written in the file, and the number of rows whose value could not be parsed.
+    // Empty values are ignored. If the header has no such column, every data row is unparseable.
+    let reader = BufReader::new(File::open(file_path)?);
+    let mut lines = reader.lines().skip(config.header_row_index);
+    let header = lines.next().transpose()?.unwrap_or_default();
+    // Quoted columns and values may contain commas
+    let column_index = split_csv_fields(&normalize_file_header(&header, config))
+        .iter()
+        .position(|column| column.trim() == date_column);
+    if column_index.is_none() {
+        warn!("Date column {} not found in header of {}", date_column, file_path);
+    }
+    let mut range: Option<((chrono::NaiveDateTime, String), (chrono::NaiveDateTime, String))> = None;
+    let mut unparseable_rows = 0;
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_csv_fields(&line);
+        let value = column_index.and_then(|index| fields.get(index)).map(|value| value.trim());
+        match value {
+            Some("") => (),
+            Some(value) => match parse_date_time(value) {
+                Some(date_time) => {
+                    let (min, max) = range.get_or_insert_with(|| {
+                        ((date_time, value.to_string()), (date_time, value.to_string()))
+                    });
+                    if date_time < min.0 {
+                        *min = (date_time, value.to_string());
+                    }
+                    if date_time > max.0 {
+                        *max = (date_time, value.to_string());
+                    }
+                }
+                None => unparseable_rows += 1,
+            },
+            None => unparseable_rows += 1,
+        }
+    }
+    Ok(match range {
+        Some((min, max)) => (Some(min.1), Some(max.1), unparseable_rows),
+        None => (None, None, unparseable_rows),
+    })
+}
+
+fn parse_date_time(value: &str) -> Option<chrono::NaiveDateTime> {
+    // Parse an ISO-8601 like date time, with space or T separator and optional fractional seconds, or a date
+    ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+}
+
+struct FileResult {
+    // Outcome of a file, as logged to upload.log, for the JSON output of the run-once and retry subcommands
+    file: String,
+    table_name: Option<String>,
+    status: &'static str,
+    bytes: Option<u64>,
+    reason: Option<String>,
+    // From the start of the file's handling until its outcome
+    duration: Duration,
+}
+
+#[derive(Default)]
+struct FileResults {
+    // Start of the handling of each file, under its current name
+    start_times: HashMap<PathBuf, Instant>,
+    results: Vec<FileResult>,
+}
+
+fn start_file_timer(file_path: &Path) {
+    // Record when the handling of a file starts, on its first event of the batch
+    if let Some(file_results) = FILE_RESULTS.lock().unwrap().as_mut() {
+        file_results.start_times.entry(file_path.to_path_buf()).or_insert_with(Instant::now);
+    }
+}
+
+fn move_file_timer(file_path: &Path, new_file_path: &Path) {
+    // Keep the start of a file's handling once it is renamed with its suffix
+    if let Some(file_results) = FILE_RESULTS.lock().unwrap().as_mut() {
+        if let Some(start_time) = file_results.start_times.get(file_path).copied() {
+            file_results.start_times.insert(new_file_path.to_path_buf(), start_time);
+        }
+    }
+}
+
+fn retry_from_manifest(
+    config: &Config,
+    hashmap: &TemplateMap,
+    manifest_path: &str,
+    json_output: bool,
+) -> std::io::Result<()> {
+    // Re-run files listed in a manifest (one path per line) through the pipeline once.
+    // With json_output, a summary with the outcome of each file is printed to stdout.
+    let manifest = File::open(manifest_path)?;
+    let start_time = Instant::now();
+    let mut file_results: Vec<FileResult> = Vec::new();
+    let mut event_vec: Vec<notify::Event> = Vec::new();
+    for line in BufReader::new(manifest).lines() {
+        let line = line?;
+        let file_path = line.trim();
+        if file_path.is_empty() || file_path.starts_with('#') {
+            continue;
+        }
+        if !Path::new(file_path).is_file() {
+            warn!("Skipping {:?} from manifest: file does not exist", file_path);
+            file_results.push(FileResult {
+                file: file_path.to_string(),
+                table_name: None,
+                status: "skipped",
+                bytes: None,
+                reason: Some("File does not exist".to_string()),
+                duration: Duration::ZERO,
+            });
+            continue;
+        }
+        event_vec.push(
+            notify::Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from(file_path)),
+        );
+    }
+    info!(
+        "Retrying {} file(s) from manifest {:?}",
+        event_vec.len(),
+        manifest_path
     );
+    handle_files_once(config, hashmap, &event_vec, file_results, start_time, json_output)
+}
+
+fn run_once(config: &Config, hashmap: &TemplateMap, json_output: bool) -> std::io::Result<()> {
+    // Scan the source directories once for csv files not handled yet, as the daemon does after an inotify
+    // overflow, and run them through the pipeline. With json_output, a summary is printed to stdout.
+    let start_time = Instant::now();
+    let watch_dirs: Vec<(PathBuf, Option<(u64, u64)>)> = config
+        .src_dirs
+        .iter()
+        .map(|src_dir| (watch_dir_path(src_dir, config), None))
+        .collect();
+    let event_vec = rescan_source_files(&watch_dirs, &HashSet::new(), hashmap, config);
+    info!("Found {} file(s) in the source directories", event_vec.len());
+    handle_files_once(config, hashmap, &event_vec, Vec::new(), start_time, json_output)
+}
+
+fn handle_files_once(
+    config: &Config,
+    hashmap: &TemplateMap,
+    event_vec: &[notify::Event],
+    mut file_results: Vec<FileResult>,
+    start_time: Instant,
+    json_output: bool,
+) -> std::io::Result<()> {
+    // Handle the events of a one-shot subcommand as a single batch. Deferred files are left for a later run.
+    // Returns an error if any file failed.
+    let mut summary = BatchSummary::default();
+    let mut batch_error = None;
+    if !event_vec.is_empty() {
+        if json_output {
+            *FILE_RESULTS.lock().unwrap() = Some(FileResults::default());
+        }
+        let handle_result = handle_csv_file_event(config, hashmap, event_vec);
+        let FileResults { start_times, results } = FILE_RESULTS.lock().unwrap().take().unwrap_or_default();
+        file_results.extend(results);
+        match handle_result {
+            Ok((deferred_events, batch_summary)) => {
+                summary = batch_summary;
+                for event in deferred_events {
+                    warn!("File {:?} was deferred and not handled", event.paths[0]);
+                    file_results.push(FileResult {
+                        file: event.paths[0].display().to_string(),
+                        table_name: None,
+                        status: "deferred",
+                        bytes: fs::metadata(&event.paths[0]).map(|attr| attr.len()).ok(),
+                        reason: None,
+                        duration: start_times.get(&event.paths[0]).map(Instant::elapsed).unwrap_or_default(),
+                    });
+                }
+            }
+            Err(e) => {
+                error!("Batch failed: {}", e);
+                batch_error = Some(e);
+            }
+        }
+    }
+    // The summary is printed even if the batch failed, so a scheduler always has a result to parse
+    if json_output {
+        let files_json: Vec<serde_json::Value> = file_results
+            .iter()
+            .map(|result| {
+                serde_json::json!({
+                    "file": result.file,
+                    "status": result.status,
+                    "table": result.table_name,
+                    "bytes": result.bytes,
+                    "duration_seconds": result.duration.as_secs_f64(),
+                    "reason": result.reason,
+                })
+            })
+            .collect();
+        let summary_json = serde_json::json!({
+            "files": files_json,
+            "total_files": summary.total_files,
+            "succeeded": summary.succeeded,
+            "failed": summary.failed,
+            "unmatched": summary.unmatched,
+            "deferred": summary.deferred,
+            "empty": summary.empty,
+            "skipped": file_results.iter().filter(|result| result.status == "skipped").count(),
+            "succeeded_bytes": summary.succeeded_bytes,
+            "duration_seconds": start_time.elapsed().as_secs_f64(),
+            "error": batch_error.as_ref().map(|e| e.to_string()),
+        });
+        println!("{}", serde_json::to_string_pretty(&summary_json).unwrap());
+    }
+    if let Some(e) = batch_error {
+        return Err(e);
+    }
+    if summary.failed > 0 {
+        return Err(std::io::Error::other(format!(
+            "{} of {} file(s) failed",
+            summary.failed, summary.total_files
+        )));
+    }
+    Ok(())
+}
+
+fn describe_config(config: &Config, hashmap: &TemplateMap) -> String {
+    // Effective configuration, as resolved from the environment and .env file, with sensitive values masked.
+    // A setting is sensitive if its name contains one of LOG_REDACT_KEYS, or if it is a URL (which may carry a token).
+    let template_count: usize = hashmap.values().map(Vec::len).sum();
+    let watched_extensions = if config.sniff_extensionless { ".csv and extensionless files" } else { ".csv files" };
+    let mut lines = vec![format!(
+        "Effective configuration ({} templates loaded, watching {}):",
+        template_count, watched_extensions
+    )];
+    let mut redacting = false;
+    for line in format!("{:#?}", config).lines() {
+        // Fields of the pretty printed struct are indented by exactly four spaces, their nested values by more
+        if let Some(field) = line.strip_prefix("    ").filter(|field| !field.starts_with(' ')) {
+            let (name, value) = field.split_once(": ").unwrap_or((field, ""));
+            let compact_name = name.replace('_', "");
+            redacting = (name.ends_with("_url")
+                || config.log_redact_keys.iter().any(|key| compact_name.contains(key.as_str())))
+                && value != "None,";
+            if redacting {
+                lines.push(format!("    {}: ***,", name));
+                continue;
+            }
+        } else if redacting && line != "}" {
+            continue;
+        }
+        lines.push(line.to_string());
+    }
+    lines.join("\n")
+}
+
+fn list_tables(hashmap: &TemplateMap, json_output: bool) {
+    // Print loaded tables with their header signature and column count, sorted by table name
+    let mut tables: Vec<(&String, &String)> = hashmap
+        .values()
+        .flatten()
+        .map(|template| (&template.table_name, &template.headers))
+        .collect();
+    tables.sort();
+    if json_output {
+        let tables_json: Vec<serde_json::Value> = tables
+            .iter()
+            .map(|(table_name, headers)| {
+                serde_json::json!({
+                    "table": table_name,
+                    "headers": headers,
+                    "column_count": column_count(headers),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&tables_json).unwrap());
+        return;
+    }
+    let table_width = tables
+        .iter()
+        .map(|(table_name, _)| table_name.len())
+        .max()
+        .unwrap_or(0)
+        .max("TABLE".len());
+    println!("{:<table_width$}  {:>7}  HEADERS", "TABLE", "COLUMNS");
+    for (table_name, headers) in tables {
+        println!("{:<table_width$}  {:>7}  {}", table_name, column_count(headers), headers);
+    }
+}
+
+fn test_match(
+    config: &Config,
+    hashmap: &TemplateMap,
+    table_name: &str,
+    csv_path: &str,
+) -> std::io::Result<()> {
+    // Run the matching logic on a sample file against one template and print the column differences
+    let template = hashmap
+        .values()
+        .flatten()
+        .find(|template| template.table_name == table_name)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No template loaded for table {}", table_name),
+            )
+        })?;
+    let csv_headers = read_header_line(csv_path, config)?;
+    let file_headers = normalize_file_header(&csv_headers, config);
+    let file_headers = file_headers.as_ref();
+    println!("Template headers: {}", template.headers);
+    println!("File headers:     {}", file_headers);
+    match find_template(file_headers, hashmap, config) {
+        Some(matched) if matched.table_name == table_name => {
+            if matched.headers == file_headers {
+                println!("Matched table {} exactly.", table_name);
+            } else {
+                println!("Matched table {} by column prefix.", table_name);
+            }
+            if config.strict_column_count
+                && matched.headers == file_headers
+                && column_count(&csv_headers) != column_count(file_headers)
+            {
+                println!(
+                    "STRICT_COLUMN_COUNT would reject the file: expected {} columns, got {}",
+                    column_count(file_headers),
+                    column_count(&csv_headers)
+                );
+            }
+            return Ok(());
+        }
+        Some(matched) => println!("File matches table {} instead of {}.", matched.table_name, table_name),
+        None => println!("File does not match table {}.", table_name),
+    }
+    let template_columns: Vec<&str> = template.headers.split(',').collect();
+    let file_columns: Vec<&str> = file_headers.split(',').collect();
+    let (missing, extra) = column_differences(&template.headers, file_headers);
+    if !missing.is_empty() {
+        println!("Missing columns: {}", missing.join(","));
+    }
+    if !extra.is_empty() {
+        println!("Extra columns:   {}", extra.join(","));
+    }
+    for (position, (template_column, file_column)) in template_columns.iter().zip(&file_columns).enumerate() {
+        if template_column != file_column {
+            println!(
+                "Column {} differs: template {:?}, file {:?}",
+                position + 1,
+                template_column,
+                file_column
+            );
+        }
+    }
+    Err(std::io::Error::other(format!("{} does not match table {}", csv_path, table_name)))
+}
+
+fn main() -> std::io::Result<()> {
+    let logger = SimpleLogger::new();
+    log::set_max_level(logger.max_level());
+    log::set_boxed_logger(Box::new(BatchLogger(logger))).unwrap();
+    let config = load_env_vars();
+    let hashmap = load_headers(&config.template_dirs, config.template_manifest.as_deref(), config.ignore_header_quotes)?;
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        None => {
+            // Also resume a queue left by a previous run, even if the delay has since been disabled
+            if config.delete_delay_seconds > 0 || Path::new(&config.delete_queue_file).exists() {
+                let delete_queue_file = config.delete_queue_file.clone();
+                thread::spawn(move || run_delayed_deletions(delete_queue_file));
+            }
+            info!("{}", describe_config(&config, &hashmap));
+            sleep_jitter("startup", config.transfer_jitter_max_ms);
+            start_ssh_control_master(&config);
+            let result = watch_for_file_changes(&config, hashmap);
+            // Batches since the last scheduled rollup are rolled up before exiting
+            if config.rollup_manifest {
+                transfer_rollup_manifest(&config);
+            }
+            stop_ssh_control_master(&config);
+            if let Err(e) = result {
+                error!("Watcher stopped: {:?}", e);
+                return Err(std::io::Error::other(e.to_string()));
+            }
+        }
+        Some("retry") => match (args.get(2).map(String::as_str), args.get(3), args.get(4).map(String::as_str)) {
+            (Some("--manifest"), Some(manifest_path), json_flag @ (None | Some("--json"))) => {
+                start_ssh_control_master(&config);
+                let result = retry_from_manifest(&config, &hashmap, manifest_path, json_flag.is_some());
+                stop_ssh_control_master(&config);
+                result?
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Usage: rsync_csv retry --manifest <file> [--json]",
+                ))
+            }
+        },
+        Some("run-once") => match args.get(2).map(String::as_str) {
+            json_flag @ (None | Some("--json")) => {
+                start_ssh_control_master(&config);
+                let result = run_once(&config, &hashmap, json_flag.is_some());
+                if config.rollup_manifest {
+                    transfer_rollup_manifest(&config);
+                }
+                stop_ssh_control_master(&config);
+                result?
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Usage: rsync_csv run-once [--json]",
+                ))
+            }
+        },
+        Some("test-match") => match (
+            args.get(2).map(String::as_str),
+            args.get(3),
+            args.get(4).map(String::as_str),
+            args.get(5),
+        ) {
+            (Some("--template"), Some(table_name), Some("--file"), Some(csv_path)) => {
+                test_match(&config, &hashmap, table_name, csv_path)?
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Usage: rsync_csv test-match --template <table> --file <csv>",
+                ))
+            }
+        },
+        Some("--print-config") => println!("{}", describe_config(&config, &hashmap)),
+        Some("status") => print_status(&config, args.get(2).map(String::as_str) == Some("--json"))?,
+        Some("list-tables") => list_tables(&hashmap, args.get(2).map(String::as_str) == Some("--json")),
+        Some(other) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown subcommand: {}", other),
+            ))
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Environment variables are shared by the test threads
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    // Removes the variables set for a test, also when loading the config panics
+    struct TestVars<'a>(&'a [(&'a str, &'a str)]);
+
+    impl Drop for TestVars<'_> {
+        fn drop(&mut self) {
+            for (key, _) in self.0 {
+                env::remove_var(key);
+            }
+        }
+    }
+
+    fn test_config(vars: &[(&str, &str)]) -> Config {
+        // Load a Config from the required settings plus vars, as the daemon does from its environment
+        let _env_lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let _test_vars = TestVars(vars);
+        let required_vars = [
+            ("SOURCE_DIR", "/tmp"),
+            ("DEST_USER", "user"),
+            ("DEST_HOST", "localhost"),
+            ("DEST_DIR", "/data"),
+            ("TEMPLATE_DIR", "/tmp"),
+            ("FILE_SUFFIX", "%Y%m%d%H%M%S"),
+            ("CSV_EVENT_WAIT_SECONDS", "1"),
+            ("CSV_EVENT_UPPER_LIMIT", "100"),
+        ];
+        for (key, value) in required_vars.iter().chain(vars) {
+            env::set_var(key, value);
+        }
+        load_env_vars()
+    }
+
+    fn write_test_file(name: &str, contents: &str) -> String {
+        // Write a file in a directory of its own, so tests running in parallel don't share files
+        let test_dir = env::temp_dir().join(format!("rsync_csv_test_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&test_dir).unwrap();
+        let file_path = test_dir.join(name);
+        fs::write(&file_path, contents).unwrap();
+        file_path.to_str().unwrap().to_string()
+    }
+
+    fn test_templates(name: &str, templates: &[(&str, &str)], ignore_quotes: bool) -> TemplateMap {
+        // Load templates from a directory of <table>_template.csv files, as TEMPLATE_DIR
+        let template_dir = env::temp_dir().join(format!("rsync_csv_test_{}_{}_templates", std::process::id(), name));
+        fs::create_dir_all(&template_dir).unwrap();
+        for (table_name, headers) in templates {
+            fs::write(template_dir.join(format!("{}_template.csv", table_name)), headers).unwrap();
+        }
+        load_headers(&[template_dir.to_str().unwrap().to_string()], None, ignore_quotes).unwrap()
+    }
+
+    #[test]
+    fn header_is_read_after_leading_junk_lines() {
+        let config = test_config(&[("HEADER_ROW_INDEX", "2")]);
+        let hashmap = test_templates("junk_lines", &[("orders", "a,b,c\n")], false);
+        let csv_path = write_test_file("junk_lines.csv", "Orders export\n\na,b,c\n1,2,3\n4,5,6\n\n");
+        assert_eq!(match_col_headers(&csv_path, &hashmap, &config).unwrap(), "orders");
+        // Rows are counted after the header only
+        assert_eq!(count_data_rows(&csv_path, &config).unwrap(), 2);
+    }
+
+    #[test]
+    fn source_directories_map_to_their_destination_prefix() {
+        let config = test_config(&[
+            ("SOURCE_DIR", "/srv/plant_a,/srv/plant_b,/srv/other"),
+            ("DEST_SUBDIR_MAP", "/srv/plant_a=site_a,/srv/plant_b=site_b"),
+        ]);
+        assert_eq!(resolve_dest_dir("/srv/plant_a/orders.csv", "orders", &config), "/data/site_a/orders");
+        assert_eq!(resolve_dest_dir("/srv/plant_b/orders.csv", "orders", &config), "/data/site_b/orders");
+        // Unmapped source directories use DEST_DIR
+        assert_eq!(resolve_dest_dir("/srv/other/orders.csv", "orders", &config), "/data/orders");
+    }
+
+    #[test]
+    fn header_with_trailing_comma_and_space_matches_template() {
+        assert_eq!(normalize_header("a,b,c, "), "a,b,c");
+        assert_eq!(normalize_header("a,b,c ,, \r\n"), "a,b,c");
+        let config = test_config(&[]);
+        let hashmap = test_templates("trailing_space", &[("orders", "a,b,c\n")], false);
+        let csv_path = write_test_file("trailing_space.csv", "a,b,c, \n1,2,3\n");
+        assert_eq!(match_col_headers(&csv_path, &hashmap, &config).unwrap(), "orders");
+    }
+
+    #[test]
+    fn header_growing_between_reads_is_matched() {
+        let config = test_config(&[("HEADER_MATCH_RETRIES", "3"), ("HEADER_MATCH_RETRY_INTERVAL_MS", "10")]);
+        let hashmap = test_templates("growing_header", &[("orders", "order_id,customer_id,amount\n")], false);
+        let csv_path = write_test_file("growing_header.csv", "order_id,cust");
+        // The writer flushes the rest of the header right after the first, partial read
+        let mut reads = 0;
+        let table_name = match_col_headers_using(&csv_path, &hashmap, &config, |path, config| {
+            let header = read_header_line(path, config);
+            reads += 1;
+            if reads == 1 {
+                let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+                file.write_all(b"omer_id,amount\n1,2,3\n").unwrap();
+            }
+            header
+        })
+        .unwrap();
+        assert_eq!(table_name, "orders");
+        assert_eq!(reads, 2);
+    }
+
+    #[test]
+    fn header_hash_ignores_column_order() {
+        assert_eq!(header_hash("a,b,c"), header_hash("c,a,b"));
+        assert_ne!(header_hash("a,b,c"), header_hash("a,b"));
+        assert_ne!(header_hash("a,b,c"), header_hash("a,b,d"));
+        // Reordered templates share a bucket and are told apart by their exact header
+        let hashmap = test_templates("header_hash", &[("orders", "a,b,c\n"), ("orders_v2", "c,a,b\n")], false);
+        let bucket = &hashmap[&header_hash("a,b,c")];
+        assert_eq!(bucket.len(), 2);
+        let config = test_config(&[]);
+        assert_eq!(find_template("c,a,b", &hashmap, &config).unwrap().table_name, "orders_v2");
+    }
+
+    #[test]
+    fn rename_columns_keeps_quoted_columns_with_commas() {
+        let config = test_config(&[("COLUMN_RENAME_MAP", "orders.cust_id=customer_id,orders.amount=total")]);
+        let file_path = write_test_file("rename.csv", "\"x, y\",cust_id,\"amount\"\r\n\"1, 2\",7,3.5\r\n");
+        let upload_file = rename_columns(&file_path, "orders", &config).unwrap();
+        assert_eq!(
+            fs::read_to_string(upload_file).unwrap(),
+            "\"x, y\",customer_id,total\r\n\"1, 2\",7,3.5\r\n"
+        );
+    }
+
+    #[test]
+    fn split_csv_fields_unquotes_fields() {
+        assert_eq!(split_csv_fields("a,b,c"), ["a", "b", "c"]);
+        assert_eq!(split_csv_fields("\"a, b\",c"), ["a, b", "c"]);
+        assert_eq!(split_csv_fields(" \"say \"\"hi\"\"\" ,x"), ["say \"hi\"", "x"]);
+        // Unquoted fields are kept as they are
+        assert_eq!(split_csv_fields(" a ,,b,"), [" a ", "", "b", ""]);
+        assert_eq!(split_csv_fields(""), [""]);
+    }
+
+    #[test]
+    fn parse_itemized_files_keeps_regular_files() {
+        let rsync_output = [
+            "sending incremental file list",
+            ">f+++++++++ new.csv",
+            "<f+++++++++ sent.csv",
+            ".f..t...... touched.csv",
+            ".f          up to date.csv",
+            "cd+++++++++ orders/",
+            "*deleting   old.csv",
+            "",
+            "sent 1,234 bytes  received 56 bytes  2,580.00 bytes/sec",
+            "total size is 1,024  speedup is 0.79",
+        ]
+        .join("\n");
+        let expected = ["new.csv", "sent.csv", "touched.csv", "up to date.csv"];
+        assert_eq!(parse_itemized_files(&rsync_output), expected.iter().map(|file| file.to_string()).collect());
+    }
+
+    #[test]
+    fn allowed_hours_parse_and_contain() {
+        let time = |value: &str| chrono::NaiveTime::parse_from_str(value, "%H:%M").unwrap();
+        let office_hours: AllowedHours = "08:00-18:30".parse().unwrap();
+        assert!(office_hours.contains(time("08:00")));
+        assert!(office_hours.contains(time("18:29")));
+        assert!(!office_hours.contains(time("18:30")));
+        assert!(!office_hours.contains(time("07:59")));
+        // A window ending before it starts spans midnight
+        let night: AllowedHours = " 22:00 - 06:00 ".parse().unwrap();
+        assert!(night.contains(time("23:15")));
+        assert!(night.contains(time("00:00")));
+        assert!(!night.contains(time("06:00")));
+        assert!(!night.contains(time("12:00")));
+        assert!("08:00".parse::<AllowedHours>().is_err());
+        assert!("08:00-08:00".parse::<AllowedHours>().is_err());
+        assert!("8am-5pm".parse::<AllowedHours>().is_err());
+    }
+
+    #[test]
+    fn strict_header_rejects_trailing_delimiters() {
+        let hashmap = test_templates("strict_header", &[("orders", "a,b,c\n")], false);
+        let csv_path = write_test_file("strict_header.csv", "a,b,c,,\r\n1,2,3,,\r\n");
+        let exact_csv_path = write_test_file("strict_header_exact.csv", " a,b,c \r\n1,2,3\r\n");
+        let trimmed_config = test_config(&[]);
+        assert_eq!(match_col_headers(&csv_path, &hashmap, &trimmed_config).unwrap(), "orders");
+        let strict_config = test_config(&[("STRICT_HEADER", "true")]);
+        assert_eq!(match_col_headers(&csv_path, &hashmap, &strict_config).unwrap(), "");
+        assert_eq!(match_col_headers(&exact_csv_path, &hashmap, &strict_config).unwrap(), "orders");
+    }
+
+    #[test]
+    fn date_column_range_reads_quoted_fields() {
+        let config = test_config(&[]);
+        let file_path = write_test_file(
+            "date_range.csv",
+            "\"name, full\",day\nJane,2024-03-02\n\"Roe, Rick\",\"2024-01-05\"\nBob,soon\n",
+        );
+        let (min, max, unparseable_rows) = date_column_range(&file_path, "day", &config).unwrap();
+        assert_eq!(min.as_deref(), Some("2024-01-05"));
+        assert_eq!(max.as_deref(), Some("2024-03-02"));
+        assert_eq!(unparseable_rows, 1);
+    }
+
+    #[test]
+    fn parse_changed_files_only_lists_reported_files() {
+        let rsync_errors = [
+            "file has vanished: \"/src/orders/daily orders_20240101.csv\"",
+            "rsync: [sender] \"/src/orders/items_20240101.csv\": file changed as we read it",
+            "WARNING: stock_20240101.csv failed verification -- update discarded (will try again).",
+            "rsync: [sender] write error: Broken pipe (32)",
+            "rsync error: some files vanished before they could be transferred (code 24) at main.c(1338)",
+        ]
+        .join("\n");
+        let changed_files = parse_changed_files(&rsync_errors);
+        let expected = ["daily orders_20240101.csv", "items_20240101.csv", "stock_20240101.csv"];
+        assert_eq!(changed_files, expected.iter().map(|file| file.to_string()).collect());
+        assert!(!changed_files.contains("customers_20240101.csv"));
+    }
+
+    #[test]
+    fn suffix_file_name_does_not_replace_existing_suffixed_file() {
+        let config = test_config(&[("FILE_SUFFIX", "fixed")]);
+        let src_file = write_test_file("orders.csv", "a,b\n1,2\n");
+        let src_dir = Path::new(&src_file).parent().unwrap();
+        fs::write(src_dir.join("orders_fixed.csv"), "waiting\n").unwrap();
+        fs::write(src_dir.join("orders_fixed_1.csv"), "waiting too\n").unwrap();
+        let src_file_with_suffix = suffix_file_name(&src_file, &config).unwrap();
+        assert_eq!(src_file_with_suffix, src_dir.join("orders_fixed_2.csv").to_str().unwrap());
+        assert!(!Path::new(&src_file).exists());
+        assert_eq!(fs::read_to_string(&src_file_with_suffix).unwrap(), "a,b\n1,2\n");
+        assert_eq!(fs::read_to_string(src_dir.join("orders_fixed.csv")).unwrap(), "waiting\n");
+        assert_eq!(fs::read_to_string(src_dir.join("orders_fixed_1.csv")).unwrap(), "waiting too\n");
+    }
+
+    #[test]
+    fn unmatched_failure_is_counted_under_unknown_table() {
+        let mut summary = BatchSummary::default();
+        summary.record_unmatched_failure("File too large: 20 bytes exceeds MAX_FILE_BYTES=10");
+        assert_eq!(summary.total_files, 1);
+        assert_eq!(summary.failed, 1);
+        let table_result = &summary.table_results[UNKNOWN_TABLE];
+        assert_eq!(table_result.failed, summary.failed);
+        assert_eq!(
+            table_result.last_error.as_ref().map(|(reason, _)| reason.as_str()),
+            Some("File too large: 20 bytes exceeds MAX_FILE_BYTES=10")
+        );
+    }
+
+    #[test]
+    fn glob_match_handles_wildcards() {
+        assert!(glob_match("orders_*.csv", "orders_20240101.csv"));
+        assert!(glob_match("orders_??.csv", "orders_01.csv"));
+        assert!(!glob_match("orders_??.csv", "orders_001.csv"));
+        assert!(glob_match("*_*_*.csv", "a_b_c_d.csv"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.csv", "orders.csv.tmp"));
+        assert!(!glob_match("orders.csv", "Orders.csv"));
+    }
+
+    #[test]
+    fn quoted_header_matches_unquoted_template() {
+        let config = test_config(&[("IGNORE_HEADER_QUOTES", "true")]);
+        let hashmap = test_templates("quoted_header", &[("orders", "order_id,customer_id,amount\n")], true);
+        let csv_path = write_test_file("quoted_header.csv", "\"order_id\", \"customer_id\",\"amount\"\n1,2,3\n");
+        assert_eq!(match_col_headers(&csv_path, &hashmap, &config).unwrap(), "orders");
+        // Without IGNORE_HEADER_QUOTES the quotes are part of the column names
+        let quoted_config = test_config(&[]);
+        let quoted_hashmap = test_templates("quoted_header_kept", &[("orders", "order_id,customer_id,amount\n")], false);
+        assert_eq!(match_col_headers(&csv_path, &quoted_hashmap, &quoted_config).unwrap(), "");
+    }
+
+    #[test]
+    fn excess_events_keep_one_event_per_file_and_drop_the_newest() {
+        let event = |name: &str| notify::Event::new(EventKind::Any).add_path(PathBuf::from(name));
+        // Repeated events of a pending file are dropped before the events of other files
+        let mut event_vec = vec![event("a.csv"), event("a.csv"), event("b.csv"), event("c.csv")];
+        let mut table_events = HashMap::from([("orders".to_string(), (vec![event("d.csv")], Instant::now()))]);
+        assert_eq!(drop_excess_events(&mut event_vec, &mut table_events, 3), 2);
+        assert_eq!(event_vec.iter().map(|e| e.paths[0].to_str().unwrap()).collect::<Vec<_>>(), ["a.csv", "b.csv"]);
+        assert_eq!(table_events["orders"].0.len(), 1);
+        assert_eq!(drop_excess_events(&mut event_vec, &mut table_events, 0), 3);
+        assert!(event_vec.is_empty());
+        assert!(table_events.is_empty());
+    }
+
+    #[test]
+    fn create_and_modify_events_of_a_write_queue_a_single_file() {
+        let config = test_config(&[("EVENT_MERGE_WINDOW_MS", "60000")]);
+        let hashmap = test_templates("merged_events", &[("orders", "order_id,amount\n")], false);
+        // Without a matching template the file is counted as unmatched and not transferred
+        let csv_path = PathBuf::from(write_test_file("merged_events.csv", "id,total\n1,2\n"));
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        let events = [EventKind::Create(CreateKind::File), modify, modify, modify]
+            .map(|kind| notify::Event::new(kind).add_path(csv_path.clone()));
+        let mut event_vec = Vec::new();
+        let mut last_event_time = Instant::now();
+        let mut table_events = HashMap::new();
+        let mut dir_event_times = HashMap::new();
+        let mut file_event_times = HashMap::new();
+        for event in &events {
+            queue_event(
+                event,
+                &mut event_vec,
+                &mut last_event_time,
+                &mut table_events,
+                &mut dir_event_times,
+                &mut file_event_times,
+                &hashmap,
+                &config,
+            );
+        }
+        assert_eq!(event_vec.len(), 1);
+        assert!(event_vec[0].kind.is_create());
+        let (deferred_events, summary) = handle_csv_file_event(&config, &hashmap, &event_vec).unwrap();
+        assert!(deferred_events.is_empty());
+        assert_eq!(summary.total_files, 1);
+        assert_eq!(summary.unmatched, 1);
+    }
+
+    #[test]
+    fn expand_env_vars_expands_and_escapes() {
+        let lookup = |name: &str| match name {
+            "HOST" => Some("db1".to_string()),
+            "HOME" => Some("/home/csv".to_string()),
+            _ => None,
+        };
+        assert_eq!(expand_env_vars("$HOME/in", lookup).unwrap(), "/home/csv/in");
+        assert_eq!(expand_env_vars("/data/${HOST}_csv", lookup).unwrap(), "/data/db1_csv");
+        assert_eq!(expand_env_vars("cost$$HOST", lookup).unwrap(), "cost$HOST");
+        assert_eq!(expand_env_vars("cost\\$HOST", lookup).unwrap(), "cost$HOST");
+        assert_eq!(expand_env_vars("100$ and $", lookup).unwrap(), "100$ and $");
+        let err = expand_env_vars("/data/$MISSING/csv", lookup).unwrap_err();
+        assert!(err.contains("unresolved variable $MISSING"), "{}", err);
+        assert!(expand_env_vars("/data/${HOST", lookup).is_err());
+    }
+
+    #[test]
+    fn keep_local_metadata_deletes_only_the_source_file() {
+        let src_file = write_test_file("ledger.csv", "a,b\n1,2\n");
+        let metadata_file = format!("{}.metadata", src_file);
+        fs::write(&metadata_file, "2024-01-01 00:00:00,csv,ledger.csv\n").unwrap();
+        let config = test_config(&[("KEEP_LOCAL_METADATA", "true")]);
+        delete_src_file_and_metadata(&src_file, &metadata_file, &config);
+        assert!(!Path::new(&src_file).exists());
+        assert!(Path::new(&metadata_file).exists());
+        // With METADATA_LEDGER_DIR the metadata file is moved there
+        let src_file = write_test_file("ledger_dir.csv", "a,b\n1,2\n");
+        let metadata_file = format!("{}.metadata", src_file);
+        fs::write(&metadata_file, "2024-01-01 00:00:00,csv,ledger_dir.csv\n").unwrap();
+        let ledger_dir = Path::new(&src_file).parent().unwrap().join("ledger");
+        let config = test_config(&[
+            ("KEEP_LOCAL_METADATA", "true"),
+            ("METADATA_LEDGER_DIR", ledger_dir.to_str().unwrap()),
+        ]);
+        delete_src_file_and_metadata(&src_file, &metadata_file, &config);
+        assert!(!Path::new(&src_file).exists());
+        assert!(!Path::new(&metadata_file).exists());
+        assert!(ledger_dir.join("ledger_dir.csv.metadata").exists());
+    }
+
+    #[test]
+    fn create_and_modify_events_within_merge_window_are_merged() {
+        let merge_window = Duration::from_millis(100);
+        let start = Instant::now();
+        let file_event = |kind: EventKind, offset_ms: u64| {
+            let event = notify::Event::new(kind).add_path(PathBuf::from("/src/orders.csv"));
+            (event, start + Duration::from_millis(offset_ms))
+        };
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        let events = [
+            file_event(EventKind::Create(notify::event::CreateKind::File), 0),
+            file_event(modify, 40),
+            file_event(modify, 80),
+            file_event(modify, 120),
+            // Past the window since the previous event, a new write of the file is queued again
+            file_event(modify, 300),
+        ];
+        let mut pending_events: Vec<notify::Event> = Vec::new();
+        let mut previous_event_time = None;
+        for (event, event_time) in &events[..4] {
+            if !is_merged_event(&pending_events, event, previous_event_time, *event_time, merge_window) {
+                pending_events.push(event.clone());
+            }
+            previous_event_time = Some(*event_time);
+        }
+        assert_eq!(pending_events.len(), 1);
+        assert!(pending_events[0].kind.is_create());
+        let (event, event_time) = &events[4];
+        assert!(!is_merged_event(&pending_events, event, previous_event_time, *event_time, merge_window));
+    }
+}